@@ -0,0 +1,330 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lenia::Real;
+
+// Math formula in x and parameters p0, p1.. for user defined function shapes, parsed once
+// into postfix program, so evaluation for every cell is just a loop over it.
+// Supports + - * / ^ (power), parentheses, numbers, pi, e and functions
+// exp, ln, log10, sqrt, abs, sin, cos, tan, tanh, floor, min, max, pow
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Expression {
+    source: String,
+    program: Vec<Op>,
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Num(Real),
+    X,
+    Param(usize),
+    Neg,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    Call1(fn(Real) -> Real),
+    Call2(fn(Real, Real) -> Real),
+}
+
+const STACK: usize = 32;  // max depth of evaluation stack, deeper formulas are refused
+const PARAMETERS: usize = 16;  // p0..p15, higher indices are refused
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(Real),
+    Ident(String),
+    Op(char),
+    Open,
+    Close,
+    Comma,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let chars: Vec<char> = source.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() { i += 1; continue; }
+        let start = i;
+        if c.is_ascii_digit() || c == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') { i += 1; }
+            // exponent like 1e-3
+            if i < chars.len() && chars[i] == 'e' && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit() || *c == '-' || *c == '+') {
+                i += 2;
+                while i < chars.len() && chars[i].is_ascii_digit() { i += 1; }
+            }
+            let text: String = chars[start..i].iter().collect();
+            tokens.push(Token::Num(text.parse().map_err(|_| format!("bad number '{}'", text))?));
+            continue;
+        }
+        if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') { i += 1; }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            continue;
+        }
+        tokens.push(match c {
+            '+' | '-' | '*' | '/' | '^' => Token::Op(c),
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ',' => Token::Comma,
+            _ => return Err(format!("unexpected '{}' at {}", c, i)),
+        });
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+// recursive descent, emits postfix program while parsing
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    program: Vec<Op>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), String> {
+        match self.tokens.get(self.pos) {
+            Some(t) if *t == token => { self.pos += 1; Ok(()) },
+            Some(t) => Err(format!("expected {:?}, found {:?}", token, t)),
+            None => Err(format!("expected {:?}, formula ends", token)),
+        }
+    }
+
+    // expr := term (('+'|'-') term)*
+    fn expr(&mut self) -> Result<(), String> {
+        self.term()?;
+        while let Some(Token::Op(c @ ('+' | '-'))) = self.peek().cloned() {
+            self.pos += 1;
+            self.term()?;
+            self.program.push(if c == '+' {Op::Add} else {Op::Sub});
+        }
+        Ok(())
+    }
+
+    // term := unary (('*'|'/') unary)*
+    fn term(&mut self) -> Result<(), String> {
+        self.unary()?;
+        while let Some(Token::Op(c @ ('*' | '/'))) = self.peek().cloned() {
+            self.pos += 1;
+            self.unary()?;
+            self.program.push(if c == '*' {Op::Mul} else {Op::Div});
+        }
+        Ok(())
+    }
+
+    // unary := ('-'|'+') unary | power, so -x^2 is -(x^2)
+    fn unary(&mut self) -> Result<(), String> {
+        match self.peek() {
+            Some(Token::Op('-')) => { self.pos += 1; self.unary()?; self.program.push(Op::Neg); Ok(()) },
+            Some(Token::Op('+')) => { self.pos += 1; self.unary() },
+            _ => self.power(),
+        }
+    }
+
+    // power := atom ('^' unary)?, right associative
+    fn power(&mut self) -> Result<(), String> {
+        self.atom()?;
+        if let Some(Token::Op('^')) = self.peek() {
+            self.pos += 1;
+            self.unary()?;
+            self.program.push(Op::Pow);
+        }
+        Ok(())
+    }
+
+    fn atom(&mut self) -> Result<(), String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Num(n)) => self.program.push(Op::Num(n)),
+            Some(Token::Open) => {
+                self.expr()?;
+                self.expect(Token::Close)?;
+            },
+            Some(Token::Ident(name)) if self.peek() == Some(&Token::Open) => {
+                self.pos += 1;
+                self.expr()?;
+                let op = match name.as_str() {
+                    "exp" => Op::Call1(Real::exp),
+                    "ln" => Op::Call1(Real::ln),
+                    "log10" => Op::Call1(Real::log10),
+                    "sqrt" => Op::Call1(Real::sqrt),
+                    "abs" => Op::Call1(Real::abs),
+                    "sin" => Op::Call1(Real::sin),
+                    "cos" => Op::Call1(Real::cos),
+                    "tan" => Op::Call1(Real::tan),
+                    "tanh" => Op::Call1(Real::tanh),
+                    "floor" => Op::Call1(Real::floor),
+                    "min" | "max" | "pow" => {
+                        self.expect(Token::Comma)?;
+                        self.expr()?;
+                        Op::Call2(match name.as_str() {
+                            "min" => Real::min,
+                            "max" => Real::max,
+                            _ => Real::powf,
+                        })
+                    },
+                    _ => return Err(format!("unknown function '{}'", name)),
+                };
+                self.expect(Token::Close)?;
+                self.program.push(op);
+            },
+            Some(Token::Ident(name)) => self.program.push(match name.as_str() {
+                "x" => Op::X,
+                "pi" => Op::Num(std::f64::consts::PI as Real),
+                "e" => Op::Num(std::f64::consts::E as Real),
+                _ => match name.strip_prefix('p').and_then(|i| i.parse::<usize>().ok()) {
+                    Some(i) if i < PARAMETERS => Op::Param(i),
+                    Some(_) => return Err(format!("'{}' is too high, use p0..p{}", name, PARAMETERS - 1)),
+                    None => return Err(format!("unknown name '{}', use x and p0, p1..", name)),
+                },
+            }),
+            Some(t) => return Err(format!("unexpected {:?}", t)),
+            None => return Err("formula ends too early".to_owned()),
+        }
+        Ok(())
+    }
+}
+
+impl Expression {
+    pub fn new(source: &str) -> Result<Self, String> {
+        let mut parser = Parser { tokens: tokenize(source)?, pos: 0, program: vec![] };
+        parser.expr()?;
+        if let Some(t) = parser.peek() { return Err(format!("unexpected {:?} after end", t)); }
+
+        let mut depth = 0usize;
+        let mut max_depth = 0;
+        parser.program.iter().for_each(|op|{
+            match op {
+                Op::Num(_) | Op::X | Op::Param(_) => depth += 1,
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow | Op::Call2(_) => depth -= 1,
+                Op::Neg | Op::Call1(_) => {},
+            }
+            max_depth = max_depth.max(depth);
+        });
+        if max_depth > STACK { return Err("formula is nested too deeply".to_owned()); }
+        Ok(Self { source: source.to_owned(), program: parser.program })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // amount of parameters formula reads, up to highest p index it uses
+    pub fn parameters(&self) -> usize {
+        self.program.iter().filter_map(|op| if let Op::Param(i) = op {Some(i + 1)} else {None}).max().unwrap_or(0)
+    }
+
+    // missing parameters are read as 0
+    pub fn eval(&self, x: Real, parameters: &[Real]) -> Real {
+        let mut stack = [0. as Real; STACK];
+        let mut top = 0;
+        for op in &self.program {
+            match *op {
+                Op::Num(n) => { stack[top] = n; top += 1; },
+                Op::X => { stack[top] = x; top += 1; },
+                Op::Param(i) => { stack[top] = parameters.get(i).copied().unwrap_or(0.); top += 1; },
+                Op::Neg => stack[top - 1] = -stack[top - 1],
+                Op::Call1(f) => stack[top - 1] = f(stack[top - 1]),
+                _ => {
+                    top -= 1;
+                    let (a, b) = (stack[top - 1], stack[top]);
+                    stack[top - 1] = match *op {
+                        Op::Add => a + b,
+                        Op::Sub => a - b,
+                        Op::Mul => a * b,
+                        Op::Div => a / b,
+                        Op::Pow => a.powf(b),
+                        Op::Call2(f) => f(a, b),
+                        _ => unreachable!(),
+                    };
+                },
+            }
+        }
+        stack[0]
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = String;
+    fn try_from(source: String) -> Result<Self, String> {
+        Self::new(&source)
+    }
+}
+
+impl From<Expression> for String {
+    fn from(e: Expression) -> String {
+        e.source
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl fmt::Debug for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Expression").field(&self.source).finish()
+    }
+}
+
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Expression {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str, x: Real) -> Real {
+        Expression::new(source).unwrap().eval(x, &[0.5, 2.])
+    }
+
+    #[test]
+    fn evaluates() {
+        assert_eq!(eval("-x^2", 0.5), -0.25);
+        assert_eq!(eval("2^-1", 0.), 0.5);
+        assert_eq!(eval("2^3^2", 0.), 512.);
+        assert_eq!(eval("1 - 2 - 3", 0.), -4.);
+        assert_eq!(eval("8 / 2 / 2", 0.), 2.);
+        assert!((eval("1e-3*x", 2.) - 0.002).abs() < 1e-9);
+        assert_eq!(eval("min(x, p0) + max(x, p1)", 1.), 2.5);
+        assert_eq!(eval("(x + 1) * p1", 1.), 4.);
+        assert!((eval("exp(-((x-p1)/p0)^2/2)", 2.) - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn counts_parameters() {
+        assert_eq!(Expression::new("x").unwrap().parameters(), 0);
+        assert_eq!(Expression::new("p0 + p3 * x").unwrap().parameters(), 4);
+        assert_eq!(Expression::new("p15").unwrap().parameters(), 16);
+        assert_eq!(Expression::new("p3").unwrap().eval(0., &[1.]), 0.);  // missing one is 0
+    }
+
+    #[test]
+    fn refuses_mistakes() {
+        for source in ["", "x +", "foo(x)", "q1", "(x", "x)", "p16", "p99999999999999999999", "min(x)", "2 $ x"] {
+            assert!(Expression::new(source).is_err(), "'{}' was accepted", source);
+        }
+        // stack depth matters, not parentheses, x+(x+(x+.. keeps every x on stack
+        let left = "(".repeat(40) + "x" + &"+1)".repeat(40);
+        assert!(Expression::new(&left).is_ok());
+        let right = "x+(".repeat(40) + "x" + &")".repeat(40);
+        assert!(Expression::new(&right).is_err());
+    }
+}
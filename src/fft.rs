@@ -0,0 +1,146 @@
+use std::{cell::RefCell, fmt, sync::Arc};
+
+use ndarray::prelude::*;
+use rayon::prelude::*;
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+
+use crate::lenia::Real;
+
+thread_local! {
+    // lane copies and rustfft scratch, one per worker thread, reused between steps
+    static SCRATCH: RefCell<Vec<Complex<Real>>> = const { RefCell::new(vec![]) };
+    // product of transform with kernel, one per thread that applies kernels, reused by all of them
+    static PRODUCT: RefCell<Array3<Complex<Real>>> = RefCell::new(Array3::from_elem((0, 0, 0), Complex::new(0., 0.)));
+}
+
+fn with_scratch<R>(len: usize, f: impl FnOnce(&mut [Complex<Real>]) -> R) -> R {
+    SCRATCH.with(|s|{
+        let mut s = s.borrow_mut();
+        if s.len() < len { s.resize(len, Complex::new(0., 0.)); }
+        f(&mut s[..len])
+    })
+}
+
+// fft plans for every axis of one world size, planning is done once
+#[derive(Clone)]
+pub struct Plan {
+    dim: (usize, usize, usize),
+    forward: Vec<Arc<dyn Fft<Real>>>,
+    inverse: Vec<Arc<dyn Fft<Real>>>,
+}
+
+impl Plan {
+    pub fn new(dim: (usize, usize, usize)) -> Self {
+        let mut planner = FftPlanner::new();
+        let lengths = [dim.0, dim.1, dim.2];
+        Self {
+            dim,
+            forward: lengths.iter().map(|n| planner.plan_fft_forward(*n)).collect(),
+            inverse: lengths.iter().map(|n| planner.plan_fft_inverse(*n)).collect(),
+        }
+    }
+
+    pub fn forward(&self, data: &mut Array3<Complex<Real>>) {
+        process(data, &self.forward);
+    }
+
+    // not normalized, result is multiplied by amount of cells
+    pub fn inverse(&self, data: &mut Array3<Complex<Real>>) {
+        process(data, &self.inverse);
+    }
+}
+
+// in place 3d fft, done as 1d ffts along every axis, axes of length 1 are skipped so
+// 2d worlds (depth 1) cost the same as plain 2d fft
+fn process(data: &mut Array3<Complex<Real>>, ffts: &[Arc<dyn Fft<Real>>]) {
+    for (axis, fft) in ffts.iter().enumerate() {
+        let n = data.len_of(Axis(axis));
+        if n == 1 { continue; }
+        let scratch_len = fft.get_inplace_scratch_len();
+        if axis == 2 && data.is_standard_layout() {
+            // last axis lanes are contiguous, so they are transformed where they are
+            data.as_slice_mut().unwrap().par_chunks_mut(n).for_each(|lane|{
+                with_scratch(scratch_len, |scratch| fft.process_with_scratch(lane, scratch));
+            });
+        } else {
+            ndarray::Zip::from(data.lanes_mut(Axis(axis))).par_for_each(|mut lane|{
+                with_scratch(n + scratch_len, |buffer|{
+                    let (buffer, scratch) = buffer.split_at_mut(n);
+                    buffer.iter_mut().zip(lane.iter()).for_each(|(b, l)| *b = *l);
+                    fft.process_with_scratch(buffer, scratch);
+                    lane.iter_mut().zip(buffer.iter()).for_each(|(l, b)| *l = *b);
+                });
+            });
+        }
+    }
+}
+
+// Kernel prepared for circular convolution with world of one size, made once when kernel
+// changes, then every step costs only transform of world there and back
+#[derive(Clone)]
+pub struct KernelSpectrum {
+    plan: Plan,
+    spectrum: Array3<Complex<Real>>,
+}
+
+impl KernelSpectrum {
+    // kernel (centered in its middle) is mirrored and wrapped around world of given size,
+    // mirroring makes it correlation like in ndarray-conv, kernels bigger than world
+    // just wrap onto themselves
+    pub fn new(kernel: &Array3<Real>, dim: (usize, usize, usize)) -> Self {
+        let plan = Plan::new(dim);
+        let mut spectrum = Array3::from_elem(dim, Complex::new(0., 0.));
+        let (kd, kw, kh) = kernel.dim();
+        kernel.indexed_iter().for_each(|((z, x, y), k)|{
+            let p = [
+                ((kd / 2) as i64 - z as i64).rem_euclid(dim.0 as i64) as usize,
+                ((kw / 2) as i64 - x as i64).rem_euclid(dim.1 as i64) as usize,
+                ((kh / 2) as i64 - y as i64).rem_euclid(dim.2 as i64) as usize,
+            ];
+            spectrum[p].re += k;
+        });
+        plan.forward(&mut spectrum);
+        let n = spectrum.len() as Real;  // rustfft doesn't normalize, so it's done here once
+        spectrum.par_mapv_inplace(|c| c / n);
+        Self { plan, spectrum }
+    }
+
+    pub fn dim(&self) -> (usize, usize, usize) {
+        self.plan.dim
+    }
+
+    // forward transform of matrix of spectrum size, can be shared by all kernels of that size
+    pub fn transform(&self, matrix: &Array3<Real>) -> Array3<Complex<Real>> {
+        let mut out = Array3::from_elem(matrix.dim(), Complex::new(0., 0.));
+        ndarray::Zip::from(&mut out).and(matrix).par_for_each(|c, m| *c = Complex::new(*m, 0.));
+        self.plan.forward(&mut out);
+        out
+    }
+
+    // circular convolution of matrix given by its transform, part of it starting at offset
+    // is written to out (of its size)
+    pub fn apply(&self, transform: &Array3<Complex<Real>>, offset: [usize; 3], out: ArrayViewMut3<Real>) {
+        PRODUCT.with(|p|{
+            let mut p = p.borrow_mut();
+            if p.dim() != self.plan.dim { *p = Array3::from_elem(self.plan.dim, Complex::new(0., 0.)); }
+            ndarray::Zip::from(&mut *p).and(transform).and(&self.spectrum).par_for_each(|c, t, k| *c = *t * *k);
+            self.plan.inverse(&mut p);
+
+            let (d, w, h) = out.dim();
+            let part = p.slice(s![offset[0]..offset[0] + d, offset[1]..offset[1] + w, offset[2]..offset[2] + h]);
+            ndarray::Zip::from(out).and(part).par_for_each(|o, c| *o = c.re);
+        });
+    }
+}
+
+impl fmt::Debug for KernelSpectrum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KernelSpectrum").field("dim", &self.plan.dim).finish()
+    }
+}
+
+impl PartialEq for KernelSpectrum {
+    fn eq(&self, other: &Self) -> bool {
+        self.spectrum == other.spectrum
+    }
+}
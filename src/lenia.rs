@@ -1,23 +1,52 @@
 #![allow(dead_code)]
 use ndarray::prelude::*;
-use ndarray_conv::*;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use strum::Display;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use rand_distr::StandardNormal;
+use std::ops::Range;
 
+use rustfft::num_complex::Complex;
+
+use crate::expr::Expression;
+use crate::fft::KernelSpectrum;
+
+// float type of whole simulation, f64 build (feature "f64") is slower, but long runs of
+// sensitive creatures don't drift away because of rounding
+#[cfg(not(feature = "f64"))]
+pub type Real = f32;
+#[cfg(feature = "f64")]
+pub type Real = f64;
 
 pub trait Cycle {
     fn next(&mut self) -> Self;
     fn previous(&mut self) -> Self;
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Display)]
 pub enum Shape {
     GaussianBump, // width, offset
     ExponentialDecay,
     SmoothTransition,
     MexicanHat,
     TripleBump,
+    Polynomial, // width, offset, alpha
+    Rectangle,
+    Linear, // heights of control points evenly spread over 0<>1, joined by lines
+    Spline, // same points joined by smooth (catmull-rom) curve
+    #[strum(to_string = "{0}")]
+    Expression(Expression), // formula in x and parameters p0, p1.., e.g. exp(-((x-p1)/p0)^2/2)
+    Sum(Vec<Term>), // w0*t0 + w1*t1.., weights are function parameters, may go below 0
+    Product(Vec<Term>), // t0^w0 * t1^w1.., so weight says how much term matters
+}
+
+// One shape of composite function, with its own parameters
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Term {
+    pub shape: Shape,
+    pub parameters: Vec<Real>,
 }
 
 impl Cycle for Shape {
@@ -27,188 +56,1311 @@ impl Cycle for Shape {
             Shape::ExponentialDecay => Shape::SmoothTransition,
             Shape::SmoothTransition => Shape::MexicanHat,
             Shape::MexicanHat => Shape::TripleBump,
-            Shape::TripleBump => Shape::GaussianBump,
+            Shape::TripleBump => Shape::Polynomial,
+            Shape::Polynomial => Shape::Rectangle,
+            Shape::Rectangle => Shape::Linear,
+            Shape::Linear => Shape::Spline,
+            Shape::Spline | Shape::Expression(_) | Shape::Sum(_) | Shape::Product(_) => Shape::GaussianBump,
         }
     }
     fn previous(&mut self) -> Self {
         match self {
-            Shape::GaussianBump => Shape::TripleBump,
+            Shape::GaussianBump | Shape::Expression(_) | Shape::Sum(_) | Shape::Product(_) => Shape::Spline,
             Shape::ExponentialDecay => Shape::GaussianBump,
             Shape::SmoothTransition => Shape::ExponentialDecay,
             Shape::MexicanHat => Shape::SmoothTransition,
             Shape::TripleBump => Shape::MexicanHat,
+            Shape::Polynomial => Shape::TripleBump,
+            Shape::Rectangle => Shape::Polynomial,
+            Shape::Linear => Shape::Rectangle,
+            Shape::Spline => Shape::Linear,
         }
     }
 }
 
-// Kernel and growth functions are the same, only diffrence is that, growth function x changes with
-// pi*r^2. Delta can be applied later
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct Function {
-    pub shape: Shape,
-    pub centering: bool,  // should it be centered at x (moved down)
-    pub parameters: Vec<f32>,  // it will be clamped to 0<>1
+// How channel uses layers output: Euler adds growth (m + delta*growth, clamped to 0<>1),
+// Asymptotic treats output as target and relaxes toward it (m + delta*(target - m)),
+// Flow moves mass along growth gradient instead of creating it (mass conserving Flow Lenia)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Display)]
+pub enum UpdateMode {
+    #[default]
+    Euler,
+    Asymptotic,
+    Flow,
 }
 
-impl Function {
-    pub fn new(shape: Shape, centering: bool, parameters: Vec<f32>) -> Self {
-        Function {
-            shape,
-            centering,
-            parameters,
+impl Cycle for UpdateMode {
+    fn next(&mut self) -> Self {
+        match self {
+            UpdateMode::Euler => UpdateMode::Asymptotic,
+            UpdateMode::Asymptotic => UpdateMode::Flow,
+            UpdateMode::Flow => UpdateMode::Euler,
+        }
+    }
+    fn previous(&mut self) -> Self {
+        match self {
+            UpdateMode::Euler => UpdateMode::Flow,
+            UpdateMode::Asymptotic => UpdateMode::Euler,
+            UpdateMode::Flow => UpdateMode::Asymptotic,
+        }
+    }
+}
+
+// Time integration of one step, higher orders run layers again at intermediate states,
+// flow mode always makes single reintegration step
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Display)]
+pub enum Integrator {
+    #[default]
+    Euler,
+    Midpoint,
+    RK4,
+}
+
+impl Cycle for Integrator {
+    fn next(&mut self) -> Self {
+        match self {
+            Integrator::Euler => Integrator::Midpoint,
+            Integrator::Midpoint => Integrator::RK4,
+            Integrator::RK4 => Integrator::Euler,
+        }
+    }
+    fn previous(&mut self) -> Self {
+        match self {
+            Integrator::Euler => Integrator::RK4,
+            Integrator::Midpoint => Integrator::Euler,
+            Integrator::RK4 => Integrator::Midpoint,
+        }
+    }
+}
+
+// What lies beyond world edges: Torus wraps around, Zero is dead border, Reflect mirrors
+// world at edges, Constant is border filled with given value
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, Display)]
+pub enum Boundary {
+    #[default]
+    Torus,
+    Zero,
+    Reflect,
+    Constant(Real),
+}
+
+impl Cycle for Boundary {
+    fn next(&mut self) -> Self {
+        match self {
+            Boundary::Torus => Boundary::Zero,
+            Boundary::Zero => Boundary::Reflect,
+            Boundary::Reflect => Boundary::Constant(1.),
+            Boundary::Constant(_) => Boundary::Torus,
+        }
+    }
+    fn previous(&mut self) -> Self {
+        match self {
+            Boundary::Torus => Boundary::Constant(1.),
+            Boundary::Zero => Boundary::Torus,
+            Boundary::Reflect => Boundary::Zero,
+            Boundary::Constant(_) => Boundary::Reflect,
+        }
+    }
+}
+
+impl Boundary {
+    // index in world that position i on axis of length n reads from, None in dead/constant border
+    fn source(&self, i: i64, n: usize) -> Option<usize> {
+        let n = n as i64;
+        match self {
+            Boundary::Torus => Some(i.rem_euclid(n) as usize),
+            Boundary::Reflect => {
+                let i = i.rem_euclid(2 * n);  // mirror includes edge cell, -1 reads 0
+                Some(if i < n {i} else {2 * n - i - 1} as usize)
+            },
+            Boundary::Zero | Boundary::Constant(_) => if (0..n).contains(&i) {Some(i as usize)} else {None},
+        }
+    }
+
+    // value at position that may lie outside of world
+    fn value(&self, m: &Array3<Real>, [z, x, y]: [i64; 3]) -> Real {
+        let (d, w, h) = m.dim();
+        match (self.source(z, d), self.source(x, w), self.source(y, h)) {
+            (Some(z), Some(x), Some(y)) => m[[z, x, y]],
+            _ => if let Boundary::Constant(c) = self {*c} else {0.},
+        }
+    }
+
+    // matrix extended by pad cells on both sides of every axis
+    fn pad(&self, m: &Array3<Real>, pad: [usize; 3]) -> Array3<Real> {
+        self.block(m, [-(pad[0] as i64), -(pad[1] as i64), -(pad[2] as i64)], padded_dim(m.dim(), pad))
+    }
+
+    // part of (infinite) world of given size starting at origin, may reach outside of matrix
+    fn block(&self, m: &Array3<Real>, origin: [i64; 3], dim: (usize, usize, usize)) -> Array3<Real> {
+        let mut out = Array3::<Real>::zeros(dim);
+        ndarray::Zip::indexed(&mut out).par_for_each(|(z, x, y), o|{
+            *o = self.value(m, [origin[0] + z as i64, origin[1] + x as i64, origin[2] + y as i64]);
+        });
+        out
+    }
+}
+
+// Distribution of noise added to channels, both have unit variance before scaling
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Display)]
+pub enum NoiseKind {
+    #[default]
+    Gaussian,
+    Uniform,
+}
+
+// Stochastic Lenia, random perturbation added to channels after every step
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Noise {
+    pub kind: NoiseKind,
+    pub amplitude: Real,  // standard deviation per unit of time, step gets amplitude * sqrt(delta)
+    pub correlation: Real,  // correlation length in cells, 0 is white noise
+}
+
+impl Noise {
+    pub fn new(kind: NoiseKind, amplitude: Real, correlation: Real) -> Self {
+        Self { kind, amplitude, correlation }
+    }
+
+    // noise for single step, white noise is smoothed by gaussian of correlation length along
+    // every axis and scaled back to unit variance, cells are drawn in fixed order so the same
+    // rng state gives the same field
+    fn field(&self, dim: (usize, usize, usize), boundary: Boundary, delta: Real, rng: &mut Pcg64) -> Array3<Real> {
+        let mut field = Array3::from_shape_simple_fn(dim, || match self.kind {
+            NoiseKind::Gaussian => rng.sample(StandardNormal),
+            NoiseKind::Uniform => rng.gen_range(-1.0..1.0) * (3. as Real).sqrt(),
+        });
+        if self.correlation > 0. {
+            let r = (3. * self.correlation).ceil() as i64;
+            let mut weights: Vec<Real> = (-r..=r).map(|i| (-((i * i) as Real) / (2. * self.correlation.powi(2))).exp()).collect();
+            let sum: Real = weights.iter().sum();
+            let norm = weights.iter().map(|w| (w / sum).powi(2)).sum::<Real>().sqrt();
+            weights.iter_mut().for_each(|w| *w /= sum * norm);
+            for axis in 0..3 {
+                let n = field.len_of(Axis(axis));
+                if n == 1 { continue; }
+                let mut smooth = Array3::<Real>::zeros(dim);
+                ndarray::Zip::indexed(&mut smooth).par_for_each(|idx, v|{
+                    let mut idx = [idx.0, idx.1, idx.2];
+                    let i = idx[axis] as i64;
+                    *v = weights.iter().zip(-r..=r).filter_map(|(w, o)|{
+                        idx[axis] = boundary.source(i + o, n)?;  // nothing comes from dead border
+                        Some(w * field[idx])
+                    }).sum();
+                });
+                field = smooth;
+            }
+        }
+        let scale = self.amplitude * delta.sqrt();
+        field.par_mapv_inplace(|v| v * scale);
+        field
+    }
+}
+
+// Flow Lenia
+const FLOW_THETA: Real = 2.;  // total mass at which flow follows only mass gradient (avoids overcrowding)
+const FLOW_N: i32 = 2;  // sharpness of transition between growth and mass gradient
+const FLOW_SPREAD: Real = 0.5;  // half size of square (cube in 3d) that mass of a cell is spread over
+const FLOW_REACH: usize = 2;  // max distance mass can travel in single step, in cells
+
+// central difference along every axis (z, x, y), edges read from boundary
+fn gradient(m: &Array3<Real>, boundary: Boundary) -> [Array3<Real>; 3] {
+    let (d, w, h) = m.dim();
+    let mut g = [Array3::<Real>::zeros((d, w, h)), Array3::<Real>::zeros((d, w, h)), Array3::<Real>::zeros((d, w, h))];
+    let [gz, gx, gy] = &mut g;
+    ndarray::Zip::indexed(gz).and(gx).and(gy).par_for_each(|(z, x, y), gz, gx, gy|{
+        let (z, x, y) = (z as i64, x as i64, y as i64);
+        *gz = ( boundary.value(m, [z + 1, x, y]) - boundary.value(m, [z - 1, x, y]) ) / 2.;
+        *gx = ( boundary.value(m, [z, x + 1, y]) - boundary.value(m, [z, x - 1, y]) ) / 2.;
+        *gy = ( boundary.value(m, [z, x, y + 1]) - boundary.value(m, [z, x, y - 1]) ) / 2.;
+    });
+    g
+}
+
+// part of cell (centered at 0, size 1) covered by spread mass centered at p, single axis
+fn overlap(p: Real) -> Real {
+    ( (0.5 as Real).min(p + FLOW_SPREAD) - (-0.5 as Real).max(p - FLOW_SPREAD) ).max(0.) / (2. * FLOW_SPREAD)
+}
+
+// moves mass of every cell by delta*flow and collects it back into cells by overlap,
+// so nothing is created or lost, only transported, walls (not torus) stop mass at edge
+fn reintegrate(matrix: &Array3<Real>, growth: &Array3<Real>, mass_sum: &Array3<Real>, delta: Real, boundary: Boundary) -> Array3<Real> {
+    let (d, w, h) = matrix.dim();
+    let u = gradient(growth, boundary);
+    let a = gradient(mass_sum, boundary);
+    let reach = FLOW_REACH as Real;
+    let wrap = boundary == Boundary::Torus;
+
+    // displacement of every cell, growth gradient in sparse places, mass gradient in crowded ones
+    let mut disp = Array3::<[Real; 3]>::from_elem((d, w, h), [0.; 3]);
+    ndarray::Zip::indexed(&mut disp).par_for_each(|(z, x, y), disp|{
+        let i = (z, x, y);
+        let alpha = (mass_sum[i] / FLOW_THETA).powi(FLOW_N).clamp(0., 1.);
+        for axis in 0..3 {
+            disp[axis] = ( delta * ( (1. - alpha) * u[axis][i] - alpha * a[axis][i] ) ).clamp(-reach, reach);
+        }
+        if !wrap {  // mass can't be moved past wall
+            [z, x, y].iter().zip([d, w, h]).enumerate().for_each(|(axis, (p, n))|{
+                disp[axis] = disp[axis].clamp(-(*p as Real), (n - 1 - p) as Real);
+            });
+        }
+    });
+
+    let r = FLOW_REACH as i64;
+    let rz = if d > 1 {r} else {0};  // flat world has nowhere to go in z
+    let mut out = Array3::<Real>::zeros((d, w, h));
+    ndarray::Zip::indexed(&mut out).par_for_each(|(z, x, y), o|{
+        for oz in -rz..=rz {
+            for ox in -r..=r {
+                for oy in -r..=r {
+                    let q = [z as i64 + oz, x as i64 + ox, y as i64 + oy];
+                    let q = if wrap {
+                        [q[0].rem_euclid(d as i64) as usize, q[1].rem_euclid(w as i64) as usize, q[2].rem_euclid(h as i64) as usize]
+                    } else if (0..d as i64).contains(&q[0]) && (0..w as i64).contains(&q[1]) && (0..h as i64).contains(&q[2]) {
+                        [q[0] as usize, q[1] as usize, q[2] as usize]
+                    } else { continue; };
+                    if matrix[q] == 0. { continue; }
+                    // moved mass center relative to this cell
+                    let [dz, dx, dy] = disp[q];
+                    *o += matrix[q] * overlap(oz as Real + dz) * overlap(ox as Real + dx) * overlap(oy as Real + dy);
+                }
+            }
+        }
+    });
+    out
+}
+
+// Meaning of one function parameter, values outside of min<>max are refused when loading
+// and ui keeps them inside, optional ones can be left out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    pub name: String,
+    pub min: Real,
+    pub max: Real,
+    pub default: Real,
+    pub required: bool,
+}
+
+impl Parameter {
+    fn new(name: &str, min: Real, max: Real, default: Real) -> Self {
+        Parameter { name: name.to_owned(), min, max, default, required: true }
+    }
+}
+
+impl Shape {
+    // parameters shape reads in order, point shapes take any amount of points (at least two),
+    // so count of present ones is needed
+    pub fn parameters(&self, count: usize) -> Vec<Parameter> {
+        let width = Parameter::new("width", 0.001, 2., 0.15);
+        let offset = Parameter::new("offset", -1., 2., 0.5);
+        match self {
+            Shape::Polynomial => vec![width, offset, Parameter { required: false, ..Parameter::new("alpha", 0.1, 16., 4.) }],
+            Shape::Linear | Shape::Spline => (0..count.max(2))
+                .map(|i| Parameter::new(&format!("y{}", i), 0., 1., if i == 1 {1.} else {0.})).collect(),
+            Shape::Expression(e) => (0..e.parameters())
+                .map(|i| Parameter::new(&format!("p{}", i), Real::MIN, Real::MAX, 0.)).collect(),
+            Shape::Sum(terms) => (0..terms.len())
+                .map(|i| Parameter::new(&format!("w{}", i), -4., 4., 1.)).collect(),
+            // negative power of term that reaches 0 would be infinite
+            Shape::Product(terms) => (0..terms.len())
+                .map(|i| Parameter::new(&format!("w{}", i), 0., 4., 1.)).collect(),
+            _ => vec![width, offset],
         }
     }
 
-    pub fn calc(&self, x: f32) -> f32 {
-        let mut y;
+    // error names first wrong parameter, for composites also the term it's in
+    fn validate(&self, parameters: &[Real]) -> Result<(), String> {
+        let declared = self.parameters(parameters.len());
+        if parameters.len() > declared.len() {
+            return Err(format!("{} takes at most {} parameters, got {}", self, declared.len(), parameters.len()));
+        }
+        for (i, p) in declared.iter().enumerate() {
+            match parameters.get(i) {
+                Some(v) if !(p.min..=p.max).contains(v) =>
+                    return Err(format!("{} is {}, has to be within {}<>{}", p.name, v, p.min, p.max)),
+                None if p.required => return Err(format!("{} is missing", p.name)),
+                _ => {},
+            }
+        }
+        if let Shape::Sum(terms) | Shape::Product(terms) = self {
+            for (i, t) in terms.iter().enumerate() {
+                t.shape.validate(&t.parameters).map_err(|e| format!("term {}: {}", i, e))?;
+            }
+        }
+        Ok(())
+    }
 
+    // raw value, before rings, clamping and centering
+    fn value(&self, x: Real, parameters: &[Real]) -> Real {
         // 0 - width, 1 - offset
-        match self.shape {
+        match self {
             Shape::GaussianBump => {
-                y = ( -( ( x - self.parameters[1]) / self.parameters[0] ).powi(2) / 2. ).exp();
+                ( -( ( x - parameters[1]) / parameters[0] ).powi(2) / 2. ).exp()
             },
             Shape::ExponentialDecay => { // comes from infinity, so have to be clamped
-                y = ( -( ( x - self.parameters[1]) / self.parameters[0] ) ).exp().clamp(0., 1.);
+                ( -( ( x - parameters[1]) / parameters[0] ) ).exp().clamp(0., 1.)
             },
             Shape::SmoothTransition => {
-                y = 1. / ( 1. + ( ( x - self.parameters[1]) / self.parameters[0] ).exp() );
+                1. / ( 1. + ( ( x - parameters[1]) / parameters[0] ).exp() )
             },
             Shape::MexicanHat => {
-                y = 1. / ( 1. + ( ( ( ( x - self.parameters[1] ) / self.parameters[0] ).powi(2) - 1. ).powi(2) ) );
+                1. / ( 1. + ( ( ( ( x - parameters[1] ) / parameters[0] ).powi(2) - 1. ).powi(2) ) )
             },
             Shape::TripleBump => { // when wide goes to 2
-                y = ( 0.6 * ( -( ( x - self.parameters[1] + 0.25 ) / self.parameters[0] ).powi(2) ).exp() ) +
-                    ( 0.8 * ( -( ( x - self.parameters[1] + 0.00 ) / self.parameters[0] ).powi(2) ).exp() ) +
-                    ( 0.6 * ( -( ( x - self.parameters[1] - 0.25 ) / self.parameters[0] ).powi(2) ).exp() ).clamp(0., 1.);
+                ( 0.6 * ( -( ( x - parameters[1] + 0.25 ) / parameters[0] ).powi(2) ).exp() ) +
+                    ( 0.8 * ( -( ( x - parameters[1] + 0.00 ) / parameters[0] ).powi(2) ).exp() ) +
+                    ( 0.6 * ( -( ( x - parameters[1] - 0.25 ) / parameters[0] ).powi(2) ).exp() ).clamp(0., 1.)
+            },
+            // (1-((x-offset)/3width)^2)^alpha, alpha defaults to 4 when not given
+            // paper kernel core (4r(1-r))^alpha is width 1/6 with offset 0.5, paper growth is 
+            // width sigma with offset mu (centering does the 2y-1 part, scaled by half)
+            Shape::Polynomial => {
+                let alpha = parameters.get(2).copied().unwrap_or(4.);
+                ( 1. - ( ( x - parameters[1] ) / ( 3. * parameters[0] ) ).powi(2) ).max(0.).powf(alpha)
+            },
+            // hard step, 1 inside offset+-width, for SmoothLife rings and Game of Life limit
+            Shape::Rectangle => {
+                if ( x - parameters[1] ).abs() <= parameters[0] {1.} else {0.}
+            },
+            // parameters are curve heights at evenly spaced x, so every one can be tuned in ui,
+            // before first and after last point curve stays flat
+            Shape::Linear => {
+                interpolate(parameters, x, false)
+            },
+            Shape::Spline => {
+                interpolate(parameters, x, true)
+            },
+            Shape::Expression(e) => {
+                e.eval(x, parameters)
+            },
+            // e.g. gaussian minus wider one makes difference of gaussians kernel
+            Shape::Sum(terms) => {
+                terms.iter().zip(parameters).map(|(t, w)| w * t.shape.value(x, &t.parameters)).sum()
+            },
+            // negative terms are cut off, fractional power of them makes no sense
+            Shape::Product(terms) => {
+                terms.iter().zip(parameters).map(|(t, w)| t.shape.value(x, &t.parameters).max(0.).powf(*w)).product()
             },
         }
+    }
+}
+
+// Kernel and growth functions are the same, only diffrence is that, growth function x changes with
+// pi*r^2. Delta can be applied later
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Function {
+    pub shape: Shape,
+    pub centering: bool,  // should it be centered at x (moved down)
+    pub parameters: Vec<Real>,  // meaning and range of every one is given by shape
+    #[serde(default)]
+    pub rings: Vec<Real>,  // peak heights of concentric shells, empty means single shell
+}
 
-        y = y.clamp(0., 1.);
+impl Function {
+    pub fn new(shape: Shape, centering: bool, parameters: Vec<Real>) -> Result<Self, String> {
+        let f = Function {
+            shape,
+            centering,
+            parameters,
+            rings: vec![],
+        };
+        f.validate()?;
+        Ok(f)
+    }
+
+    // error names first wrong parameter, functions read from files should be checked with it
+    pub fn validate(&self) -> Result<(), String> {
+        self.shape.validate(&self.parameters)
+    }
+
+    // new shape keeps parameters that still fit, missing ones get defaults and extra are dropped
+    pub fn set_shape(&mut self, shape: Shape) {
+        let declared = shape.parameters(self.parameters.len());
+        self.parameters.truncate(declared.len());
+        self.parameters.iter_mut().zip(&declared).for_each(|(v, p)| if !(p.min..=p.max).contains(v) {*v = p.default});
+        let present = self.parameters.len();
+        self.parameters.extend(declared[present..].iter().filter(|p| p.required).map(|p| p.default));
+        self.shape = shape;
+    }
+
+    // parameter changed by given amount, but kept within its range
+    pub fn adjust(&mut self, i: usize, by: Real) {
+        let Some(p) = self.shape.parameters(self.parameters.len()).into_iter().nth(i) else { return; };
+        if let Some(v) = self.parameters.get_mut(i) { *v = (*v + by).clamp(p.min, p.max); }
+    }
+
+    pub fn calc(&self, x: Real) -> Real {
+        // with rings x range is split into equal parts, each one mapped back onto 0<>1 and
+        // scaled by its ring height, beyond last ring function is zero
+        let (x, height) = if self.rings.is_empty() { (x, 1.) } else {
+            let br = x * self.rings.len() as Real;
+            match self.rings.get(br.floor() as usize) {
+                Some(h) => (br.fract(), *h),
+                None => (br, 0.),
+            }
+        };
+
+        let mut y = self.shape.value(x, &self.parameters);
+        // formulas can leave their domain (sqrt(x-0.5), ln(0)), single nan cell would spread
+        // through kernel normalization to whole world
+        if !y.is_finite() { y = 0.; }
+
+        // sums may be negative (inhibiting ring of kernel), others stay positive
+        let low = if let Shape::Sum(_) = self.shape {-1.} else {0.};
+        y = (y * height).clamp(low, 1.);
         if self.centering { y -= 0.5; }
 
         y
     }
 }
 
+// curve through points evenly spread over 0<>1, straight or catmull-rom between them
+// (it passes through every point and needs only its neighbours, so it's cheap per cell)
+fn interpolate(points: &[Real], x: Real, smooth: bool) -> Real {
+    match points.len() {
+        0 => return 0.,
+        1 => return points[0],
+        _ => {},
+    }
+    let segments = points.len() - 1;
+    let at = x.clamp(0., 1.) * segments as Real;
+    let i = (at.floor() as usize).min(segments - 1);
+    let t = at - i as Real;
+    let (p1, p2) = (points[i], points[i + 1]);
+    if !smooth { return p1 + (p2 - p1) * t; }
+
+    // missing neighbours at ends are made by mirroring, so curve keeps its slope there
+    let p0 = if i > 0 {points[i - 1]} else {2. * p1 - p2};
+    let p3 = if i + 2 < points.len() {points[i + 2]} else {2. * p2 - p1};
+    0.5 * ( 2. * p1 + (p2 - p0) * t + (2. * p0 - 5. * p1 + 4. * p2 - p3) * t * t
+        + (3. * p1 - p0 - 3. * p2 + p3) * t * t * t )
+}
+
+fn padded_dim(dim: (usize, usize, usize), pad: [usize; 3]) -> (usize, usize, usize) {
+    (dim.0 + 2 * pad[0], dim.1 + 2 * pad[1], dim.2 + 2 * pad[2])
+}
+
+// How layer convolves its input: Fft multiplies spectra of whole world, Direct sums kernel
+// taps around every cell, which is cheaper for small kernels, chosen by cost in prepare
+#[derive(Debug, Clone, Copy, Default, PartialEq, Display)]
+pub enum Convolution {
+    #[default]
+    Fft,
+    Direct,
+}
+
+// rough cost of single fft (forward and inverse are about the same) per cell and log2 of cells,
+// relative to single kernel tap of direct one, from convolution_costs test on worlds 512 to 1024
+// cells wide and 64 to 96 cells deep, taps of 3d kernels jump between planes so they are slower
+const FFT_COST: Real = 8.;
+const FFT_COST_3D: Real = 4.5;
+
+// Direction dependence of kernel in x-y plane, default one keeps kernel rotationally symmetric.
+// Kernel is multiplied by 1 + strength*cos(harmonics*(angle of cell - angle) + twist*distance),
+// so 1 harmonic prefers one direction, k of them make k-fold symmetric kernel and twist bends
+// lobes into spiral (chiral kernel). Aspect squeezes kernel across angle into ellipse
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Anisotropy {
+    pub harmonics: usize,
+    pub strength: Real,  // 0<>1, so kernel doesn't go negative
+    pub angle: Real,  // radians
+    pub twist: Real,  // radians at kernel edge
+    pub aspect: Real,  // width across angle relative to radius, 0<>1
+}
+
+impl Default for Anisotropy {
+    fn default() -> Self {
+        Anisotropy { harmonics: 0, strength: 0., angle: 0., twist: 0., aspect: 1. }
+    }
+}
+
+impl Anisotropy {
+    // distance of cell from kernel center (relative to radius) and factor kernel is multiplied by
+    fn apply(&self, [z, x, y]: [Real; 3], radius: Real) -> (Real, Real) {
+        let (sin, cos) = self.angle.sin_cos();
+        let along = x * cos + y * sin;
+        let across = (y * cos - x * sin) / self.aspect.clamp(0.05, 1.);
+        let d = ( z * z + along * along + across * across ).sqrt() / radius;
+        if self.harmonics == 0 { return (d, 1.); }
+        let phase = self.harmonics as Real * across.atan2(along) + self.twist * d;
+        (d, 1. + self.strength.clamp(0., 1.) * phase.cos())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Layer {
     pub kernel: Function,
-    kernel_lookup: Array2<f32>,
+    kernel_lookup: Array3<Real>,
     pub growth_map: Function,
     pub channel_id: usize, // number of channel that will be used as input
-    matrix_out: Array2<f32>,
+    pub target_id: usize, // number of channel that growth will be added to
+    pub weight: Real, // h coefficient, relative strength of this layer in target channel
     pub radius: usize,
+    #[serde(default)]
+    pub anisotropy: Anisotropy,
+    #[serde(default)]
+    pub kernel_matrix: Option<Array3<Real>>,  // used as kernel lookup instead of kernel function
+    #[serde(default = "default_samples")]
+    pub samples: usize,  // kernel cell is averaged from samples^2 (^3 in 3d) points, 1 is just its center
+    #[serde(skip)]
+    spectra: Vec<KernelSpectrum>,  // kernel lookup in frequency domain, for every size of transform in use
+    #[serde(skip)]
+    taps: Vec<([usize; 3], Real)>,  // nonzero cells of kernel lookup, for direct convolution
+    #[serde(skip)]
+    pub convolution: Convolution,
 }
 
+pub(crate) fn default_samples() -> usize { 1 }
+
 impl Layer {
     pub fn new(
         kernel: Function,
         growth_map: Function,
         channel_id: usize,
+        target_id: usize,
+        weight: Real,
         radius: usize
     ) -> Self {
         Layer { 
-            kernel, kernel_lookup: Array2::<f32>::zeros((radius * 2 + 1, radius * 2 + 1)),
-            growth_map, channel_id, matrix_out: Default::default(), radius
+            kernel, kernel_lookup: Array3::<Real>::zeros((1, radius * 2 + 1, radius * 2 + 1)),
+            growth_map, channel_id, target_id, weight, radius, anisotropy: Anisotropy::default(), kernel_matrix: None, samples: 1,
+            spectra: vec![], taps: vec![], convolution: Convolution::Fft,
         }
     }
 
-    fn generate_kernel_lookup(&mut self) {
-        for x in -(self.radius as i64)..=self.radius as i64 {
-            for y in -(self.radius as i64)..=self.radius as i64 {
-                let r = ( (x*x+y*y) as f32 ).sqrt();
-                self.kernel_lookup[[(x+self.radius as i64) as usize, (y+self.radius as i64) as usize]] 
-                    = self.kernel.calc(r/self.radius as f32);
-            }    
+    // kernel is a ball in 3d worlds, flat disc when depth is 1, old spectra are dropped so
+    // they're remade in prepare
+    pub(crate) fn generate_kernel_lookup(&mut self, depth: usize) -> Result<(), String> {
+        let mut lookup;
+        if let Some(m) = &self.kernel_matrix {
+            // given kernel is centered in its middle cell, 2d worlds take its middle slice
+            let mz = m.dim().0 / 2;
+            lookup = if depth > 1 {m.clone()} else {m.slice(s![mz..mz + 1, .., ..]).to_owned()};
+        } else {
+            let r = self.radius as i64;
+            let rz = if depth > 1 {r} else {0};
+            // supersampling points are spread evenly inside cell, single one is its center, it
+            // smooths sharp shapes and small radii, so kernel looks the same at any radius
+            let n = self.samples.max(1);
+            let nz = if depth > 1 {n} else {1};
+            let offsets = |n: usize| (0..n).map(move |i| (i as Real + 0.5) / n as Real - 0.5);
+            lookup = Array3::<Real>::zeros(((rz * 2 + 1) as usize, self.radius * 2 + 1, self.radius * 2 + 1));
+            // 3d kernel with many samples is up to millions of calls, so cells go in parallel
+            ndarray::Zip::indexed(&mut lookup).par_for_each(|(z, x, y), k|{
+                let (z, x, y) = (z as i64 - rz, x as i64 - r, y as i64 - r);
+                let mut sum = 0.;
+                for oz in offsets(nz) {
+                    for ox in offsets(n) {
+                        for oy in offsets(n) {
+                            let p = [z as Real + oz, x as Real + ox, y as Real + oy];
+                            let (d, factor) = self.anisotropy.apply(p, self.radius as Real);
+                            sum += self.kernel.calc(d) * factor;
+                        }
+                    }
+                }
+                *k = sum / (nz * n * n) as Real;
+            });
         }
-        self.kernel_lookup /= self.kernel_lookup.sum(); // no matter kernel radius, sum off ideal
+        // zero or negative total (empty rectangle, difference of gaussians with too strong
+        // negative part) can't be normalized, layer keeps its old kernel then
+        let sum = lookup.sum();
+        if !(sum.is_finite() && sum > 0.) { return Err(format!("kernel sums to {}, has to be positive", sum)); }
+        self.kernel_lookup = lookup / sum; // no matter kernel radius, sum off ideal
         // convolution will always be equal to 1
+        self.taps = self.kernel_lookup.indexed_iter().filter(|(_, k)| **k != 0.)
+            .map(|((z, x, y), k)| ([z, x, y], *k)).collect();
+        self.spectra.clear();
+        Ok(())
+    }
+
+    pub fn kernel_lookup(&self) -> &Array3<Real> {
+        &self.kernel_lookup
+    }
+
+    // cells kernel reaches on both sides of every axis
+    fn reach(&self) -> [usize; 3] {
+        let (kd, kw, kh) = self.kernel_lookup.dim();
+        [kd / 2, kw / 2, kh / 2]
+    }
+
+    // cells needed on both sides of every axis for fft when world isn't torus
+    fn pad(&self, boundary: Boundary) -> [usize; 3] {
+        match boundary {
+            Boundary::Torus => [0; 3],
+            _ => self.reach(),
+        }
+    }
+
+    // direct convolution is used when summing all taps for every cell is cheaper than
+    // inverse fft of world (or tile) extended by pad, forward one counts only when no other
+    // layer shares it
+    fn choose(&mut self, dim: (usize, usize, usize), pad: [usize; 3], forward: bool) {
+        let fft_dim = padded_dim(dim, pad);
+        let fft_cells = (fft_dim.0 * fft_dim.1 * fft_dim.2) as Real;
+        let transforms = if forward {2.} else {1.};
+        let fft_cost = transforms * fft_cells * fft_cells.log2() * if dim.0 > 1 {FFT_COST_3D} else {FFT_COST};
+        let direct_cost = (dim.0 * dim.1 * dim.2) as Real * self.taps.len() as Real;
+        self.convolution = if direct_cost < fft_cost {Convolution::Direct} else {Convolution::Fft};
+    }
+
+    // spectrum is made only when kernel changed or size of transform is new, there are at
+    // most two sizes in use (whole world and tile), so older ones are dropped
+    fn prepare(&mut self, fft_dim: (usize, usize, usize)) {
+        if self.spectra.iter().any(|s| s.dim() == fft_dim) { return; }
+        if self.spectra.len() == 2 { self.spectra.remove(0); }
+        self.spectra.push(KernelSpectrum::new(&self.kernel_lookup, fft_dim));
     }
 
-    fn run(&mut self, channel: &Channel) {
-        // convoluted matrix
-        self.matrix_out = channel.matrix.conv_2d_fft(&self.kernel_lookup, PaddingSize::Same, PaddingMode::Circular).unwrap();
-        self.matrix_out.par_map_inplace(|x|{
-            *x = self.growth_map.calc(*x);
+    fn spectrum(&self, fft_dim: (usize, usize, usize)) -> &KernelSpectrum {
+        self.spectra.iter().find(|s| s.dim() == fft_dim).unwrap()
+    }
+
+    // input channel transform, after prepare
+    fn transform(&self, matrix: &Array3<Real>) -> Array3<Complex<Real>> {
+        self.spectrum(matrix.dim()).transform(matrix)
+    }
+
+    // convolution of whole world or of every active tile written into out (world size),
+    // cells outside of active tiles are 0 (no mass within reach)
+    fn convolve(&self, inputs: &Inputs, out: &mut Array3<Real>) {
+        let c = self.channel_id;
+        match inputs {
+            Inputs::World { pads, reaches, channels } => {
+                self.convolve_input(&channels[c], pads[c], reaches[c], out.view_mut());
+            },
+            Inputs::Tiles { size, reaches, tiles } => {
+                out.fill(0.);
+                let dim = out.dim();
+                tiles.iter().for_each(|([tx, ty], channels)|{
+                    let (x, y) = (tx * size, ty * size);  // edge tiles may be smaller
+                    let part = out.slice_mut(s![.., x..(x + size).min(dim.1), y..(y + size).min(dim.2)]);
+                    self.convolve_input(&channels[c], reaches[c], reaches[c], part);
+                });
+            },
+        }
+    }
+
+    // transform is of channel extended by pad, extended matrix is extended by reach, out is
+    // cut from middle of it
+    fn convolve_input(&self, input: &Input, pad: [usize; 3], reach: [usize; 3], out: ArrayViewMut3<Real>) {
+        match self.convolution {
+            Convolution::Fft => {
+                let transform = input.transform.as_ref().unwrap();
+                self.spectrum(transform.dim()).apply(transform, pad, out);
+            },
+            Convolution::Direct => self.convolve_direct(input.extended.as_ref().unwrap(), reach, out),
+        }
+    }
+
+    // input is channel extended by given reach (at least reach of this layer) with boundary,
+    // so every tap is scaled add of contiguous y lane, mirrored same way as fft one
+    fn convolve_direct(&self, input: &Array3<Real>, reach: [usize; 3], mut out: ArrayViewMut3<Real>) {
+        let own = self.reach();
+        let o = [reach[0] - own[0], reach[1] - own[1], reach[2] - own[2]];
+        let (_, w, h) = input.dim();
+        let input = input.as_slice().unwrap();
+        ndarray::Zip::indexed(out.lanes_mut(Axis(2))).par_for_each(|(z, x), mut lane|{
+            let lane = lane.as_slice_mut().unwrap();
+            let n = lane.len();
+            lane.fill(0.);
+            self.taps.iter().for_each(|(t, k)|{
+                let start = ((z + t[0] + o[0]) * w + x + t[1] + o[1]) * h + t[2] + o[2];
+                lane.iter_mut().zip(&input[start..start + n]).for_each(|(l, m)| *l += k * m);
+            });
+        });
+    }
+
+    // growth of convoluted matrix added to (not yet normalized) rate of target channel
+    fn grow(&self, convoluted: &Array3<Real>, rate: &mut Array3<Real>, mode: UpdateMode) {
+        // target has to stay in 0<>1, so centering is reverted
+        let shift = if mode == UpdateMode::Asymptotic && self.growth_map.centering {0.5} else {0.};
+        ndarray::Zip::from(rate).and(convoluted).par_for_each(|r, c|{
+            *r += self.weight * (self.growth_map.calc(*c) + shift);
         });
     }
 }
 
-// Array3 ????? Could be xddd
+// channel prepared for layers reading it, transformed for fft ones and extended for direct
+// ones, each only when needed
+struct Input {
+    transform: Option<Array3<Complex<Real>>>,
+    extended: Option<Array3<Real>>,
+}
+
+// inputs of all channels for one evaluation, of whole world or of every active tile (tiles
+// are extended by reach for both kinds of convolution)
+enum Inputs {
+    World { pads: Vec<[usize; 3]>, reaches: Vec<[usize; 3]>, channels: Vec<Input> },
+    Tiles { size: usize, reaches: Vec<[usize; 3]>, tiles: Vec<([usize; 2], Vec<Input>)> },
+}
+
+// matrix is (z, x, y), 2d worlds have depth 1
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Channel {
-    pub matrix: Array2<f32>,
-    matrix_out: Array2<f32>,
-    layer_counter: usize,
+    pub matrix: Array3<Real>,
 }
 
 impl Channel {
-    pub fn new(matrix: Array2<f32>) -> Self {
-        Self { 
-            matrix_out: Array2::<f32>::zeros(matrix.dim()),
-            matrix, 
-            layer_counter: 0,
-        }
-    }
-
-    // things to do after layer computation
-    fn finish(&mut self, delta: f32) {
-        self.matrix_out /= self.layer_counter as f32 ;  // change is divided by amount of layers
-        self.matrix_out *= delta;   // incorporate delta
-        self.layer_counter = 0;
-        ndarray::Zip::from(&mut self.matrix).and(&mut self.matrix_out).par_for_each(|m, m_out|{
-            *m = (*m + *m_out).clamp(0., 1.);  // add corrections to matrix
-            *m_out = 0.;  // zero corrections for next turn
-        });
+    pub fn new(matrix: Array3<Real>) -> Self {
+        Self { matrix }
+    }
+
+    // things to do after whole step is computed
+    fn finish(&mut self, matrix: Array3<Real>, noise: Option<&Array3<Real>>, mode: UpdateMode, mask: Option<&Array3<Real>>, mask_value: Real) {
+        self.matrix = matrix;
+        if let Some(noise) = noise {
+            // noise can't make cells negative, or overfull outside of flow mode
+            let max = if mode == UpdateMode::Flow {Real::INFINITY} else {1.};
+            ndarray::Zip::from(&mut self.matrix).and(noise).par_for_each(|m, n|{
+                *m = (*m + n).clamp(0., max);
+            });
+        }
+        if let Some(mask) = mask {
+            ndarray::Zip::from(&mut self.matrix).and(mask).par_for_each(|m, k|{
+                if *k > 0. { *m = mask_value; }  // obstacles always hold the same value
+            });
+        }
     }
 }
 
+// applies rate to matrix according to update mode
+fn advance(matrix: &mut Array3<Real>, rate: &Array3<Real>, delta: Real, mode: UpdateMode, boundary: Boundary, mass_sum: Option<&Array3<Real>>) {
+    match mode {
+        UpdateMode::Euler => {
+            ndarray::Zip::from(matrix).and(rate).par_for_each(|m, r|{
+                *m = (*m + delta * *r).clamp(0., 1.);  // add corrections to matrix
+            });
+        },
+        UpdateMode::Asymptotic => {
+            ndarray::Zip::from(matrix).and(rate).par_for_each(|m, r|{
+                *m += delta * *r;  // relax toward target
+            });
+        },
+        UpdateMode::Flow => {
+            *matrix = reintegrate(matrix, rate, mass_sum.unwrap(), delta, boundary);
+        },
+    }
+}
+
+// state moved by delta*rate, used for intermediate steps of integrators
+fn shifted(state: &[Array3<Real>], rate: &[Array3<Real>], delta: Real, mode: UpdateMode) -> Vec<Array3<Real>> {
+    state.iter().zip(rate).map(|(s, r)|{
+        let mut s = s.clone();
+        s.scaled_add(delta, r);
+        if mode == UpdateMode::Euler { s.par_mapv_inplace(|x| x.clamp(0., 1.)); }
+        s
+    }).collect()
+}
+
+const MIN_DELTA: Real = 1e-4;  // adaptive delta won't go below, so simulation always moves forward
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Eco {
     pub channels: Vec<Channel>,
     pub layers: Vec<Layer>,
-    pub delta: f32,
+    pub delta: Real,
+    pub tolerance: Option<Real>,  // max local error per step, with it delta is adapted every step
+    pub effective_delta: Real,  // delta actually used, differs from delta only when adaptive
+    pub mode: UpdateMode,
+    pub integrator: Integrator,
+    pub boundary: Boundary,
+    pub mask: Option<Array3<Real>>,  // obstacles, cells above 0 are walls/holes, same size as channels
+    pub mask_value: Real,  // value forced into masked cells after every step
+    pub mask_exclude: bool,  // masked cells are read as 0 by layers, no matter their value
+    pub tile: Option<usize>,  // tile size of sparse evaluation, without it whole world is always convolved
+    pub tiles: Option<(usize, usize)>,  // active and all tiles in last step, None when whole world was convolved
     pub size: (usize, usize),
     pub cycles: usize,
-    pub fitness: f32,  // f>0.25 full; 0>f>0.1 life
+    pub time: f64,  // simulated time, sum of all used deltas
+    pub noise: Option<Noise>,
+    pub seed: u64,  // rng seed, same seed gives the same random soups and noise bit for bit, at most i64::MAX so it fits toml
+    pub rng: Pcg64,
+    pub fitness: Real,  // f>0.25 full; 0>f>0.1 life
+    pub mass_error: Real,  // relative change of total mass in last step, ~0 in flow mode
 }
 
 impl Eco {
-    pub fn new(size: (usize, usize), delta: f32, cycles: usize, channels: Vec<Channel>, layers: Vec<Layer>) -> Self {
+    pub fn new(size: (usize, usize), delta: Real, cycles: usize, channels: Vec<Channel>, layers: Vec<Layer>) -> Self {
 
         Self { channels, layers, 
-            delta, size, 
-            cycles, fitness: 0.
+            delta, tolerance: None, effective_delta: delta, 
+            mode: UpdateMode::default(), integrator: Integrator::default(), boundary: Boundary::default(), 
+            mask: None, mask_value: 0., mask_exclude: false, tile: None, tiles: None, size, 
+            cycles, time: 0., noise: None, seed: 0, rng: Pcg64::seed_from_u64(0), fitness: 0., mass_error: 0.
         }
     }
 
-    pub fn init(&mut self) {
-        self.layers.par_iter_mut().for_each(|l|{
-            l.generate_kernel_lookup();
+    // has to be called after every kernel change, error names first layer whose kernel couldn't
+    // be made, all others are remade anyway
+    pub fn init(&mut self) -> Result<(), String> {
+        let depth = self.depth();
+        let results: Vec<Result<(), String>> = self.layers.par_iter_mut().enumerate().map(|(i, l)|{
+            l.generate_kernel_lookup(depth).map_err(|e| format!("layer {} {}", i, e))
+        }).collect();
+        if let Some(ch) = self.channels.first() {
+            self.prepare(ch.matrix.dim());
+        }
+        results.into_iter().collect()
+    }
+
+    // biggest of given per layer sizes for every channel, over layers reading it with given
+    // convolution (any when None), so channel is extended once for all of them
+    fn input_pads(&self, convolution: Option<Convolution>, pad: impl Fn(&Layer) -> [usize; 3]) -> Vec<[usize; 3]> {
+        (0..self.channels.len()).map(|c|{
+            let reads = |l: &&Layer| l.channel_id == c && convolution.is_none_or(|cv| l.convolution == cv);
+            self.layers.iter().filter(reads).fold([0; 3], |a, l|{
+                let p = pad(l);
+                [a[0].max(p[0]), a[1].max(p[1]), a[2].max(p[2])]
+            })
+        }).collect()
+    }
+
+    // convolution of every layer for given size, first as if forward transform of its channel
+    // was shared, then layer left as the only fft reader of channel pays for it alone
+    fn choose(&mut self, dim: (usize, usize, usize), pad: impl Fn(&Layer) -> [usize; 3]) {
+        self.layers.iter_mut().for_each(|l|{
+            let p = pad(l);
+            l.choose(dim, p, false);
         });
+        for c in 0..self.channels.len() {
+            let mut readers = self.layers.iter_mut().filter(|l| l.channel_id == c && l.convolution == Convolution::Fft);
+            if let (Some(l), None) = (readers.next(), readers.next()) {
+                let p = pad(l);
+                l.choose(dim, p, true);
+            }
+        }
     }
 
-    pub fn evaluate(&mut self) {
-        self.layers.par_iter_mut().for_each(|l|{
-            l.run(&self.channels[l.channel_id]);
+    // convolution of every layer is chosen and kernel spectra are made for size of their
+    // input channel transform, returns fft pads and direct reaches of channels
+    fn prepare(&mut self, dim: (usize, usize, usize)) -> (Vec<[usize; 3]>, Vec<[usize; 3]>) {
+        let boundary = self.boundary;
+        self.choose(dim, |l| l.pad(boundary));
+        let pads = self.input_pads(Some(Convolution::Fft), |l| l.pad(boundary));
+        let reaches = self.input_pads(Some(Convolution::Direct), |l| l.reach());
+        self.layers.par_iter_mut().filter(|l| l.convolution == Convolution::Fft).for_each(|l|{
+            l.prepare(padded_dim(dim, pads[l.channel_id]));
         });
+        (pads, reaches)
+    }
 
-        self.channels.par_iter_mut().for_each(|ch|{
-            self.layers.iter().for_each(|l|{
-                ch.layer_counter += 1;
-                ch.matrix_out.scaled_add(1., &l.matrix_out); // add to output matrix
-            });
-            ch.finish(self.delta);
+    // sets or clears obstacle in ball around position, mask is created when needed
+    pub fn paint_mask(&mut self, position: [usize; 3], radius: usize, wall: bool) {
+        let dim = self.channels[0].matrix.dim();
+        let mask = self.mask.get_or_insert_with(|| Array3::<Real>::zeros(dim));
+        let r = radius as i64;
+        let rz = if dim.0 > 1 {r} else {0};
+        for z in -rz..=rz {
+            for x in -r..=r {
+                for y in -r..=r {
+                    if z*z + x*x + y*y > r*r { continue; }
+                    let p = [position[0] as i64 + z, position[1] as i64 + x, position[2] as i64 + y];
+                    if p.iter().zip([dim.0, dim.1, dim.2]).any(|(p, n)| !(0..n as i64).contains(p)) { continue; }
+                    mask[[p[0] as usize, p[1] as usize, p[2] as usize]] = if wall {1.} else {0.};
+                }
+            }
+        }
+    }
+
+    // restarts rng from given seed
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Pcg64::seed_from_u64(seed);
+    }
+
+    // fills box of channel with random values from own rng, cells in fixed order so it's
+    // always the same for the same seed
+    pub fn soup(&mut self, channel: usize, z: Range<usize>, x: Range<usize>, y: Range<usize>) {
+        let matrix = &mut self.channels[channel].matrix;
+        for z in z {
+            for x in x.clone() {
+                for y in y.clone() {
+                    matrix[[z, x, y]] = self.rng.gen_range(0.0..1.0);
+                }
+            }
+        }
+    }
+
+    // 1 for flat worlds
+    pub fn depth(&self) -> usize {
+        self.channels.first().map_or(1, |ch| ch.matrix.dim().0)
+    }
+
+    pub fn mass(&self) -> Real {
+        self.channels.iter().map(|ch| ch.matrix.sum()).sum()
+    }
+
+    // every channel is transformed once for all fft layers reading it and extended once
+    // for all direct ones, always for biggest kernel
+    fn world_inputs(&mut self, input: &[Array3<Real>]) -> Inputs {
+        let (dim, boundary) = (input[0].dim(), self.boundary);
+        let (pads, reaches) = self.prepare(dim);
+        let channels = input.par_iter().enumerate().map(|(c, m)|{
+            let fft = self.layers.iter().find(|l| l.channel_id == c && l.convolution == Convolution::Fft);
+            let direct = self.layers.iter().any(|l| l.channel_id == c && l.convolution == Convolution::Direct);
+            Input {
+                transform: fft.map(|l| if pads[c] == [0; 3] {l.transform(m)} else {l.transform(&boundary.pad(m, pads[c]))}),
+                extended: direct.then(|| boundary.pad(m, reaches[c])),
+            }
+        }).collect();
+        self.tiles = None;
+        Inputs::World { pads, reaches, channels }
+    }
+
+    // tiles (x, y) that have to be convolved in tiled mode, ones with any nonzero cell in any
+    // channel and all within kernel reach of them, tiles span whole depth, None when world is
+    // too dense for it to pay off or when constant boundary feeds every edge
+    fn active_tiles(&self, input: &[Array3<Real>]) -> Option<Vec<[usize; 2]>> {
+        let tile = self.tile?;
+        if matches!(self.boundary, Boundary::Constant(c) if c != 0.) { return None; }
+        let dim = input[0].dim();
+        let n = (dim.1.div_ceil(tile), dim.2.div_ceil(tile));
+        let mut occupied = Array2::<bool>::from_elem(n, false);
+        ndarray::Zip::indexed(&mut occupied).par_for_each(|(tx, ty), o|{
+            *o = input.iter().any(|m| m.slice(s![.., tx * tile..((tx + 1) * tile).min(dim.1), ty * tile..((ty + 1) * tile).min(dim.2)])
+                .iter().any(|v| *v != 0.));
+        });
+
+        let reach = self.input_pads(None, |l| l.reach()).into_iter().fold([0; 3], |a, p|{
+            [a[0].max(p[0]), a[1].max(p[1]), a[2].max(p[2])]
+        });
+        let halo = [reach[1].div_ceil(tile) as i64, reach[2].div_ceil(tile) as i64];
+        let torus = self.boundary == Boundary::Torus;
+        let mut active = Array2::<bool>::from_elem(n, false);
+        occupied.indexed_iter().filter(|(_, o)| **o).for_each(|((tx, ty), _)|{
+            for x in tx as i64 - halo[0]..=tx as i64 + halo[0] {
+                for y in ty as i64 - halo[1]..=ty as i64 + halo[1] {
+                    let (x, y) = if torus {(x.rem_euclid(n.0 as i64), y.rem_euclid(n.1 as i64))} else {(x, y)};
+                    if (0..n.0 as i64).contains(&x) && (0..n.1 as i64).contains(&y) {
+                        active[[x as usize, y as usize]] = true;
+                    }
+                }
+            }
+        });
+        let tiles: Vec<[usize; 2]> = active.indexed_iter().filter(|(_, a)| **a).map(|((x, y), _)| [x, y]).collect();
+
+        // every tile is convolved as block extended by reach, so it's worth it only when
+        // blocks together are smaller than whole world
+        let block = padded_dim((dim.0, tile, tile), reach);
+        let full = padded_dim(dim, reach);
+        if tiles.len() * block.0 * block.1 * block.2 >= full.0 * full.1 * full.2 { return None; }
+        Some(tiles)
+    }
+
+    // overlap-save convolution of active tiles only, every tile is convolved as block extended
+    // by reach of channel and middle of it is kept, so result is the same as for whole world
+    fn tile_inputs(&mut self, input: &[Array3<Real>], tiles: Vec<[usize; 2]>) -> Inputs {
+        let (dim, boundary, size) = (input[0].dim(), self.boundary, self.tile.unwrap());
+        let tile_dim = (dim.0, size, size);
+        let reaches = self.input_pads(None, |l| l.reach());
+        self.choose(tile_dim, |l| l.reach());
+        self.layers.iter_mut().filter(|l| l.convolution == Convolution::Fft).for_each(|l|{
+            l.prepare(padded_dim(tile_dim, reaches[l.channel_id]));
+        });
+
+        self.tiles = Some((tiles.len(), dim.1.div_ceil(size) * dim.2.div_ceil(size)));
+        let tiles = tiles.into_iter().map(|[tx, ty]|{
+            let channels = input.par_iter().zip(&reaches).enumerate().map(|(c, (m, r))|{
+                if !self.layers.iter().any(|l| l.channel_id == c) { return Input { transform: None, extended: None }; }
+                let origin = [-(r[0] as i64), (tx * size) as i64 - r[1] as i64, (ty * size) as i64 - r[2] as i64];
+                let block = boundary.block(m, origin, padded_dim(tile_dim, *r));
+                let fft = self.layers.iter().find(|l| l.channel_id == c && l.convolution == Convolution::Fft);
+                let direct = self.layers.iter().any(|l| l.channel_id == c && l.convolution == Convolution::Direct);
+                Input { transform: fft.map(|l| l.transform(&block)), extended: direct.then_some(block) }
+            }).collect();
+            ([tx, ty], channels)
+        }).collect();
+        Inputs::Tiles { size, reaches, tiles }
+    }
+
+    // runs all layers on given state, returns rate of change for every channel
+    fn rates(&mut self, state: &[Array3<Real>]) -> Vec<Array3<Real>> {
+        let mode = self.mode;
+        let masked: Vec<Array3<Real>>;
+        let input = match (&self.mask, self.mask_exclude) {
+            (Some(mask), true) => {
+                masked = state.iter().map(|m|{
+                    let mut m = m.clone();
+                    ndarray::Zip::from(&mut m).and(mask).par_for_each(|m, k| if *k > 0. { *m = 0.; });
+                    m
+                }).collect();
+                &masked
+            },
+            _ => state,
+        };
+        let inputs = match self.active_tiles(input) {
+            Some(tiles) => self.tile_inputs(input, tiles),
+            None => self.world_inputs(input),
+        };
+
+        // layers go one by one (every part of them is parallel on its own) and add growth
+        // straight into rate of their target, so one convolution buffer is enough
+        let dim = state[0].dim();
+        let mut rates = vec![Array3::<Real>::zeros(dim); state.len()];
+        let mut weight_sums = vec![0.; state.len()];
+        let mut convoluted = Array3::<Real>::zeros(dim);
+        self.layers.iter().for_each(|l|{
+            l.convolve(&inputs, &mut convoluted);
+            l.grow(&convoluted, &mut rates[l.target_id], mode);
+            weight_sums[l.target_id] += l.weight.abs();
+        });
+
+        // zero when no layer targets channel, in asymptotic mode it's target - state
+        rates.iter_mut().zip(weight_sums).zip(state).for_each(|((rate, weight_sum), state)|{
+            if weight_sum == 0. { return; }
+            *rate /= weight_sum;  // change is divided by summed weights of layers
+            if mode == UpdateMode::Asymptotic { *rate -= state; }
+        });
+        rates
+    }
+
+    // single integrator step of given state, channels are not touched
+    fn step(&mut self, state: &[Array3<Real>], delta: Real) -> Vec<Array3<Real>> {
+        let k1 = self.rates(state);
+        let rate = match (self.mode, self.integrator) {
+            (UpdateMode::Flow, _) | (_, Integrator::Euler) => k1,
+            (_, Integrator::Midpoint) => {
+                self.rates(&shifted(state, &k1, delta / 2., self.mode))
+            },
+            (_, Integrator::RK4) => {
+                let k2 = self.rates(&shifted(state, &k1, delta / 2., self.mode));
+                let k3 = self.rates(&shifted(state, &k2, delta / 2., self.mode));
+                let k4 = self.rates(&shifted(state, &k3, delta, self.mode));
+                k1.into_iter().zip(k2).zip(k3).zip(k4).map(|(((mut k1, k2), k3), k4)|{
+                    k1.scaled_add(2., &k2);
+                    k1.scaled_add(2., &k3);
+                    k1 += &k4;
+                    k1 / 6.
+                }).collect()
+            },
+        };
+
+        // flow needs mass of all channels together
+        let mass_sum = if self.mode == UpdateMode::Flow {
+            let mut sum = Array3::<Real>::zeros(state[0].dim());
+            state.iter().for_each(|m| sum += m);
+            Some(sum)
+        } else {None};
+
+        let mut next = state.to_vec();
+        next.par_iter_mut().zip(rate.par_iter()).for_each(|(m, r)|{
+            advance(m, r, delta, self.mode, self.boundary, mass_sum.as_ref());
+        });
+        next
+    }
+
+    // step doubling, one full step is compared with two half steps, difference is local error
+    // and delta is grown or shrunk to keep it near tolerance, returns result and used delta
+    fn adaptive_step(&mut self, state: &[Array3<Real>], tolerance: Real) -> (Vec<Array3<Real>>, Real) {
+        let order = match (self.mode, self.integrator) {
+            (UpdateMode::Flow, _) | (_, Integrator::Euler) => 1,
+            (_, Integrator::Midpoint) => 2,
+            (_, Integrator::RK4) => 4,
+        };
+        loop {
+            let delta = self.effective_delta;
+            let full = self.step(state, delta);
+            let half = self.step(state, delta / 2.);
+            let double = self.step(&half, delta / 2.);
+            let error = full.iter().zip(&double).map(|(f, d)|{
+                ndarray::Zip::from(f).and(d).par_fold(|| 0. as Real, |e, f, d| e.max((f - d).abs()), |a, b| a.max(b))
+            }).fold(0., Real::max);
+
+            let factor = if error > 0. {(0.9 * (tolerance / error).powf(1. / (order as Real + 1.))).clamp(0.2, 2.)} else {2.};
+            self.effective_delta = (delta * factor).clamp(MIN_DELTA, 1.);
+            if error <= tolerance || delta <= MIN_DELTA {
+                return (double, delta);
+            }
+        }
+    }
+
+    pub fn evaluate(&mut self) {
+        let mass = self.mass();
+
+        let state: Vec<Array3<Real>> = self.channels.iter().map(|ch| ch.matrix.clone()).collect();
+        let (next, delta) = match self.tolerance {
+            Some(tolerance) => self.adaptive_step(&state, tolerance),
+            None => (self.step(&state, self.delta), self.delta),
+        };
+
+        // noise fields are drawn one after another, so they don't depend on threads
+        let (dim, boundary, noise, rng) = (state[0].dim(), self.boundary, self.noise, &mut self.rng);
+        let fields: Vec<Option<Array3<Real>>> = state.iter().map(|_| noise.map(|n| n.field(dim, boundary, delta, rng))).collect();
+        self.channels.par_iter_mut().zip(next).zip(fields).for_each(|((ch, m), noise)|{
+            ch.finish(m, noise.as_ref(), self.mode, self.mask.as_ref(), self.mask_value);
         });
 
         self.cycles += 1;
+        self.time += f64::from(delta);
+        self.mass_error = if mass > 0. {(self.mass() - mass).abs() / mass} else {0.};
 
         // calculate fitness
         self.fitness = 0.;
         self.channels.iter().for_each(|ch|{
             self.fitness += ch.matrix.mean().unwrap();
         });
-        //self.fitness /= self.channels.len() as f32 * 10000.;
+        //self.fitness /= self.channels.len() as Real * 10000.;
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(radius: usize) -> Layer {
+        Layer::new(
+            Function::new(Shape::GaussianBump, false, vec![0.15, 0.5]).unwrap(),
+            Function::new(Shape::GaussianBump, true, vec![0.15, 0.3]).unwrap(),
+            0, 0, 1., radius,
+        )
+    }
+
+    fn random(dim: (usize, usize, usize), seed: u64) -> Array3<Real> {
+        let mut rng = Pcg64::seed_from_u64(seed);
+        Array3::from_shape_simple_fn(dim, || rng.gen_range(0.0..1.0))
+    }
+
+    fn max_difference(a: &Array3<Real>, b: &Array3<Real>) -> Real {
+        ndarray::Zip::from(a).and(b).fold(0., |e: Real, a, b| e.max((a - b).abs()))
+    }
+
+    // convolution of whole matrix with given method, like world_inputs does it
+    fn convolve(l: &mut Layer, m: &Array3<Real>, boundary: Boundary, convolution: Convolution) -> Array3<Real> {
+        l.convolution = convolution;
+        let (pad, reach) = (l.pad(boundary), l.reach());
+        let input = match convolution {
+            Convolution::Fft => {
+                let padded = boundary.pad(m, pad);
+                l.prepare(padded.dim());
+                Input { transform: Some(l.transform(&padded)), extended: None }
+            },
+            Convolution::Direct => Input { transform: None, extended: Some(boundary.pad(m, reach)) },
+        };
+        let mut out = Array3::<Real>::zeros(m.dim());
+        l.convolve_input(&input, pad, reach, out.view_mut());
+        out
+    }
+
+    #[test]
+    fn fft_matches_direct() {
+        let boundaries = [Boundary::Torus, Boundary::Zero, Boundary::Reflect, Boundary::Constant(0.5)];
+        for depth in [1, 12] {
+            let m = random((depth, 24, 20), 1);
+            let mut l = layer(4);
+            l.anisotropy = Anisotropy { harmonics: 3, strength: 0.8, angle: 0.4, twist: 2., aspect: 0.7 };
+            l.generate_kernel_lookup(depth).unwrap();
+            for boundary in boundaries {
+                let fft = convolve(&mut l, &m, boundary, Convolution::Fft);
+                let direct = convolve(&mut l, &m, boundary, Convolution::Direct);
+                let e = max_difference(&fft, &direct);
+                assert!(e < 1e-5, "{} depth {}: fft and direct differ by {}", boundary, depth, e);
+            }
+        }
     }
 
+    #[test]
+    fn tiles_match_whole_world() {
+        for boundary in [Boundary::Torus, Boundary::Zero] {
+            let mut matrix = Array3::<Real>::zeros((1, 128, 128));
+            matrix.slice_mut(s![.., 4..14, 6..16]).assign(&random((1, 10, 10), 2));
+            let mut whole = Eco::new((128, 128), 0.1, 0, vec![Channel::new(matrix)], vec![layer(5)]);
+            whole.boundary = boundary;
+            whole.init().unwrap();
+            let mut tiled = whole.clone();
+            tiled.tile = Some(16);
+            whole.evaluate();
+            tiled.evaluate();
+            assert!(tiled.tiles.is_some(), "{}: tiled mode fell back to whole world", boundary);
+            let e = max_difference(&whole.channels[0].matrix, &tiled.channels[0].matrix);
+            assert!(e < 1e-5, "{}: tiled and whole world differ by {}", boundary, e);
+        }
+    }
+
+    #[test]
+    fn flow_conserves_mass() {
+        let mut matrix = Array3::<Real>::zeros((1, 64, 64));
+        matrix.slice_mut(s![.., 16..40, 20..44]).assign(&random((1, 24, 24), 3));
+        let mut eco = Eco::new((64, 64), 0.2, 0, vec![Channel::new(matrix)], vec![layer(6)]);
+        eco.mode = UpdateMode::Flow;
+        eco.init().unwrap();
+        let mass = eco.mass();
+        for _ in 0..10 { eco.evaluate(); }
+        let drift = (eco.mass() - mass).abs() / mass;
+        assert!(drift < 1e-4, "flow changed mass by {}", drift);
+    }
+
+    // kernel that sums to 0 is refused, layer keeps old kernel and world stays finite
+    fn assert_kernel_refused(kernel: Function) {
+        let mut eco = Eco::new((32, 32), 0.1, 0, vec![Channel::new(random((1, 32, 32), 4))], vec![layer(4)]);
+        eco.init().unwrap();
+        let old = eco.layers[0].kernel_lookup().clone();
+        eco.layers[0].kernel = kernel;
+        assert!(eco.init().is_err());
+        assert_eq!(*eco.layers[0].kernel_lookup(), old);
+        eco.evaluate();
+        assert!(eco.channels[0].matrix.iter().all(|v| v.is_finite()));
+    }
+
+    #[test]
+    fn zero_sum_kernel_is_refused() {
+        let g = || Term { shape: Shape::GaussianBump, parameters: vec![0.15, 0.5] };
+        assert_kernel_refused(Function::new(Shape::Sum(vec![g(), g()]), false, vec![1., -1.]).unwrap());
+    }
+
+    #[test]
+    fn empty_kernel_is_refused() {
+        assert_kernel_refused(Function::new(Shape::Rectangle, false, vec![0.001, 2.]).unwrap());
+        assert_kernel_refused(Function::new(Shape::Linear, false, vec![0., 0., 0.]).unwrap());
+    }
+
+    // soup and noise come only from seeded rng
+    fn seeded(seed: u64) -> Eco {
+        let mut eco = Eco::new((48, 48), 0.1, 0, vec![Channel::new(Array3::zeros((1, 48, 48)))], vec![layer(5)]);
+        eco.noise = Some(Noise::new(NoiseKind::Gaussian, 0.05, 1.5));
+        eco.reseed(seed);
+        eco.soup(0, 0..1, 12..36, 12..36);
+        eco.init().unwrap();
+        for _ in 0..5 { eco.evaluate(); }
+        eco
+    }
+
+    #[test]
+    fn same_seed_makes_same_world() {
+        assert_eq!(seeded(42).channels[0].matrix, seeded(42).channels[0].matrix);
+        assert_ne!(seeded(42).channels[0].matrix, seeded(43).channels[0].matrix);
+    }
+
+    // costs behind FFT_COST constants, run with
+    // cargo test --release -- --ignored --nocapture convolution_costs
+    #[test]
+    #[ignore]
+    fn convolution_costs() {
+        use std::time::Instant;
+        let time = |f: &mut dyn FnMut()| {
+            f();
+            let start = Instant::now();
+            for _ in 0..5 { f(); }
+            start.elapsed().as_secs_f64() / 5.
+        };
+        for (dim, radius) in [((1, 512, 512), 8), ((1, 1024, 1024), 8), ((64, 128, 128), 4), ((96, 192, 192), 4)] {
+            let m = random(dim, 5);
+            let mut l = layer(radius);
+            l.generate_kernel_lookup(dim.0).unwrap();
+            l.prepare(dim);
+            let cells = (dim.0 * dim.1 * dim.2) as f64;
+            let transforms = cells * cells.log2();
+            let input = Input { transform: Some(l.transform(&m)), extended: Some(Boundary::Torus.pad(&m, l.reach())) };
+            let mut out = Array3::<Real>::zeros(dim);
+            let forward = time(&mut || { l.transform(&m); });
+            l.convolution = Convolution::Fft;
+            let inverse = time(&mut || l.convolve_input(&input, [0; 3], l.reach(), out.view_mut()));
+            l.convolution = Convolution::Direct;
+            let direct = time(&mut || l.convolve_input(&input, [0; 3], l.reach(), out.view_mut()));
+            let tap = direct / (cells * l.taps.len() as f64);
+            println!("{:?}: tap {:.2e}s, inverse fft {:.2} taps, forward fft {:.2} taps per cell and log2 of cells",
+                dim, tap, inverse / transforms / tap, forward / transforms / tap);
+        }
+    }
 }
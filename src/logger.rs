@@ -9,36 +9,150 @@ use ndarray::prelude::*;
 use rayon::prelude::*;
 use unique_id::{Generator, string::StringGenerator};
 use macroquad::prelude::*;
+use rand_pcg::Pcg64;
 
-use crate::lenia::{Channel, Eco, Function, Layer};
+use crate::lenia::{default_samples, Anisotropy, Boundary, Channel, Eco, Function, Integrator, Layer, Noise, Real, UpdateMode};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct LayerData {
     kernel: Function,
     growth_map: Function,
     matrix_id: String,
-    radius: usize
+    #[serde(default)]
+    target_id: Option<String>, // older saves have no target, it's the same as input then
+    #[serde(default = "default_weight")]
+    weight: Real,
+    radius: usize,
+    #[serde(default)]
+    anisotropy: Anisotropy,
+    #[serde(default)]
+    kernel_id: Option<String>,  // matrix used as kernel instead of kernel function
+    #[serde(default)]
+    kernel_image: Option<String>,  // grayscale png (or .bin matrix) path to import as kernel on load
+    #[serde(default = "default_samples")]
+    samples: usize,
 }
+impl LayerData {
+    fn kernel_ids(layers: &[LayerData]) -> Vec<String> {
+        layers.iter().filter_map(|l| l.kernel_id.clone()).collect()
+    }
+
+    // matrices are looked up among given ones, None when kernel matrix is missing
+    fn layer(&self, channel_id: usize, target_id: usize, matrices: &[MatrixData]) -> Option<Layer> {
+        let mut layer = Layer::new(self.kernel.clone(), self.growth_map.clone(), channel_id, target_id, self.weight, self.radius);
+        layer.anisotropy = self.anisotropy;
+        layer.samples = self.samples;
+        layer.kernel_matrix = match &self.kernel_id {
+            Some(uid) => Some(matrices.iter().find(|m| m.uid == *uid)?.matrix.clone()),
+            None => None,
+        };
+        Some(layer)
+    }
+}
+fn default_weight() -> Real { 1. }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct MatrixData {
+    uid: String,
+    matrix: Array3<Real>,
+}
+// matrix values as saved to .bin, in precision of build that saved them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum MatrixValues {
+    F32(Array3<f32>),
+    F64(Array3<f64>),
+}
+impl MatrixValues {
+    #[cfg(not(feature = "f64"))]
+    fn new(m: &Array3<Real>) -> Self { MatrixValues::F32(m.clone()) }
+    #[cfg(feature = "f64")]
+    fn new(m: &Array3<Real>) -> Self { MatrixValues::F64(m.clone()) }
+
+    // values converted to precision of this build
+    #[allow(clippy::unnecessary_cast)]  // one of casts is always to the same type
+    fn into_real(self) -> Array3<Real> {
+        match self {
+            MatrixValues::F32(m) => m.mapv(|x| x as Real),
+            MatrixValues::F64(m) => m.mapv(|x| x as Real),
+        }
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedMatrixData {
+    uid: String,
+    values: MatrixValues,
+}
+const MATRIX_MAGIC: &[u8] = b"LNM4";
+// .bin files saved before precision was recorded, always f32
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct F32MatrixData {
+    uid: String,
+    matrix: Array3<f32>,
+}
+const F32_MATRIX_MAGIC: &[u8] = b"LNM3";
+// .bin files saved before 3d worlds, they don't start with any magic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LegacyMatrixData {
     uid: String,
     matrix: Array2<f32>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TomlData {
     uid: String,
-    delta: f32,
+    delta: Real,
+    #[serde(default)]
+    tolerance: Option<Real>,
+    #[serde(default)]
+    mode: UpdateMode,
+    #[serde(default)]
+    integrator: Integrator,
+    #[serde(default)]
+    boundary: Boundary,
+    #[serde(default)]
+    mask_value: Real,
+    #[serde(default)]
+    mask_exclude: bool,
+    #[serde(default)]
+    tile: Option<usize>,
     size: (usize, usize),
     cycles: usize,
-    fitness: f32,
+    #[serde(default)]
+    time: f64,
+    #[serde(default)]
+    seed: u64,
+    #[serde(default, with = "rng_state", skip_serializing_if = "Option::is_none")]
+    rng: Option<Pcg64>,  // rng where saved run stopped, so it continues the same as if it never did
+    fitness: Real,
+    #[serde(default)]
+    noise: Option<Noise>,
     layer: Vec<LayerData>,
 }
+// rng state is kept as hex of its bincode, toml has no 128 bit integers
+mod rng_state {
+    use rand_pcg::Pcg64;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(rng: &Option<Pcg64>, s: S) -> Result<S::Ok, S::Error> {
+        rng.as_ref().map(|r|{
+            bincode::serialize(r).unwrap().iter().map(|b| format!("{:02x}", b)).collect::<String>()
+        }).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Pcg64>, D::Error> {
+        let Some(hex) = Option::<String>::deserialize(d)? else { return Ok(None); };
+        let bytes = (0..hex.len()).step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+            .collect::<Option<Vec<u8>>>().ok_or_else(|| D::Error::custom("rng state isn't hex"))?;
+        bincode::deserialize(&bytes).map(Some).map_err(D::Error::custom)
+    }
+}
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct InstanceData {
     uid: String,
     nick: String,
     toml: String,
     matrix: Vec<String>,
+    #[serde(default)]
+    mask: Option<String>,  // obstacle matrix uid
     active: bool
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,6 +161,49 @@ struct TomlCorrelations {
 }
 
 
+// .bin matrix file in current version
+fn write_matrix(m: &MatrixData) -> Vec<u8> {
+    let mut buffer = MATRIX_MAGIC.to_vec();
+    buffer.extend(serialize(&SavedMatrixData { uid: m.uid.clone(), values: MatrixValues::new(&m.matrix) }).unwrap());
+    buffer
+}
+
+// .bin matrix file of any version
+fn read_matrix(buffer: &[u8]) -> Result<MatrixData, bincode::Error> {
+    Ok(if let Some(b) = buffer.strip_prefix(MATRIX_MAGIC) {
+        let saved: SavedMatrixData = deserialize(b)?;
+        MatrixData { uid: saved.uid, matrix: saved.values.into_real() }
+    } else if let Some(b) = buffer.strip_prefix(F32_MATRIX_MAGIC) {
+        let saved: F32MatrixData = deserialize(b)?;
+        MatrixData { uid: saved.uid, matrix: MatrixValues::F32(saved.matrix).into_real() }
+    } else {
+        let legacy: LegacyMatrixData = deserialize(buffer)?;
+        MatrixData { uid: legacy.uid, matrix: MatrixValues::F32(legacy.matrix.insert_axis(Axis(0))).into_real() }
+    })
+}
+
+// kernel from png (brightness times alpha, image x and y like in world preview) or .bin matrix,
+// it's normalized later by layer, so only shape matters
+fn read_kernel(path: &str) -> Result<Array3<Real>, String> {
+    let buffer = fs::read(path).map_err(|e| e.to_string())?;
+    let matrix = if !path.ends_with(".png") {
+        read_matrix(&buffer).map(|m| m.matrix).map_err(|e| e.to_string())?
+    } else {
+        let img = Image::from_file_with_format(&buffer, Some(ImageFormat::Png)).map_err(|e| e.to_string())?;
+        let (w, h) = (img.width(), img.height());
+        Array3::from_shape_fn((1, w, h), |(_, x, y)|{
+            let [r, g, b, a] = img.get_pixel(x as u32, y as u32).to_vec().to_array();
+            ((r + g + b) / 3. * a) as Real
+        })
+    };
+    // black or transparent image can't be normalized
+    let sum = matrix.sum();
+    if !(sum.is_finite() && sum > 0.) {
+        return Err(format!("kernel sums to {}, has to be positive", sum));
+    }
+    Ok(matrix)
+}
+
 #[derive(Debug, Clone)]
 pub struct Logger {
     tomls: Vec<TomlData>,
@@ -64,7 +221,8 @@ impl Logger {
         correlations: TomlCorrelations {correlation: vec![]}, gen: StringGenerator::default() }
     }
     
-    pub fn image(&mut self, matrix: &Array2<f32>) -> ( (usize, usize), &Vec<u8>) {
+    // single z slice of a channel
+    pub fn image(&mut self, matrix: ArrayView2<Real>) -> ( (usize, usize), &Vec<u8>) {
         self.buffer_slice.resize_with(matrix.len() * 4, || {0});
         let size = ( matrix.len_of(Axis(0)), matrix.len_of(Axis(1)) );
         self.buffer_slice.par_chunks_mut(4).enumerate().for_each(|(i, x)|{
@@ -79,25 +237,41 @@ impl Logger {
         ( size, &self.buffer_slice)
     }
 
+    // obstacles overlay, transparent where there is no mask
+    pub fn mask_image(&mut self, mask: ArrayView2<Real>) -> ( (usize, usize), &Vec<u8>) {
+        self.visu_slice.resize_with(mask.len() * 4, || {0});
+        let size = ( mask.len_of(Axis(0)), mask.len_of(Axis(1)) );
+        self.visu_slice.par_chunks_mut(4).enumerate().for_each(|(i, x)|{
+            let wall = mask[[i%size.0, i/size.0]] > 0.;
+            x.copy_from_slice(if wall {&[96, 96, 96, 255]} else {&[0, 0, 0, 0]});
+        });
+        ( size, &self.visu_slice)
+    }
+
     // returns pushed instance index
     // translates lenia simple id to uid via slice
     fn push_toml(&mut self, eco: &Eco, matrix_uids: &[String]) -> &String {
         let toml;
         let mut layer_data = vec![];
-        eco.layers.iter().for_each(|l|{
+        for l in &eco.layers {
+            let kernel_id = l.kernel_matrix.as_ref().map(|m| self.push_matrix(m).to_string());
             layer_data.push(LayerData {
-                kernel: l.kernel.clone(), growth_map: l.growth_map.clone(), matrix_id: matrix_uids[l.channel_id].clone(), radius: l.radius
+                kernel: l.kernel.clone(), growth_map: l.growth_map.clone(), matrix_id: matrix_uids[l.channel_id].clone(), 
+                target_id: Some(matrix_uids[l.target_id].clone()), weight: l.weight, radius: l.radius, anisotropy: l.anisotropy,
+                kernel_id, kernel_image: None, samples: l.samples
             });
-        });
+        }
         let uid = self.gen.next_id();
         toml = TomlData {
-            delta: eco.delta, size: eco.size, cycles: eco.cycles, fitness: eco.fitness, layer: layer_data, uid: uid.clone()
+            delta: eco.delta, tolerance: eco.tolerance, mode: eco.mode, integrator: eco.integrator, boundary: eco.boundary,
+            mask_value: eco.mask_value, mask_exclude: eco.mask_exclude, tile: eco.tile,
+            size: eco.size, cycles: eco.cycles, time: eco.time, seed: eco.seed, rng: Some(eco.rng.clone()), fitness: eco.fitness, noise: eco.noise, layer: layer_data, uid: uid.clone()
         };
         self.tomls.push(toml);
         &self.tomls.last().unwrap().uid
     }
     // returns matrix index
-    pub fn push_matrix(&mut self, m: &Array2<f32>) -> &String {
+    pub fn push_matrix(&mut self, m: &Array3<Real>) -> &String {
         let uid =  self.gen.next_id();
         self.matrices.push( MatrixData { uid: uid.clone(), matrix: m.clone() } );
         &self.matrices.last().unwrap().uid
@@ -109,12 +283,13 @@ impl Logger {
         eco.channels.iter().for_each(|ch|{
             matrix.push(self.push_matrix(&ch.matrix).to_string());
         });
+        let mask = eco.mask.as_ref().map(|m| self.push_matrix(m).to_string());
         let uid = self.gen.next_id();
         let instance = InstanceData {
             uid,
             nick,
             toml: self.push_toml(eco, &matrix).to_string(),
-            matrix, active: true
+            matrix, mask, active: true
         };
         self.correlations.correlation.push(instance);
         &self.correlations.correlation.last().unwrap().uid
@@ -165,11 +340,36 @@ impl Logger {
         }
         
         let mut layers = vec![];
-        toml.layer.iter().for_each(|l|{
+        for l in &toml.layer {
             let id = matrix_hashmap.iter().position(|m| *m == l.matrix_id).unwrap();
-            layers.push(Layer::new(l.kernel.clone(), l.growth_map.clone(), id, l.radius));
-        });
-        Some(Eco::new(toml.size, toml.delta, toml.cycles, channels, layers))
+            let target = match &l.target_id {
+                Some(t) => matrix_hashmap.iter().position(|m| m == t).unwrap(),
+                None => id,
+            };
+            layers.push(l.layer(id, target, &self.matrices)?);
+        }
+        let mask = match &instance.mask {
+            Some(uid) => match self.matrices.iter().find(|m| m.uid == *uid) {
+                Some(m) => Some(m.matrix.clone()),
+                None => return None,
+            },
+            None => None,
+        };
+
+        let mut eco = Eco::new(toml.size, toml.delta, toml.cycles, channels, layers);
+        eco.mask = mask;
+        eco.mask_value = toml.mask_value;
+        eco.mask_exclude = toml.mask_exclude;
+        eco.tile = toml.tile;
+        eco.mode = toml.mode;
+        eco.integrator = toml.integrator;
+        eco.boundary = toml.boundary;
+        eco.tolerance = toml.tolerance;
+        eco.time = toml.time;
+        eco.reseed(toml.seed);
+        if let Some(rng) = toml.rng { eco.rng = rng; }  // older saves start again from seed
+        eco.noise = toml.noise;
+        Some(eco)
     }
 
     // Return false if there is no instance at index
@@ -181,7 +381,7 @@ impl Logger {
         self.tomls[real_idx] = toml;
         true
     }
-    fn update_matrix(&mut self, uid: &String, matrix: Array2<f32>) -> bool {
+    fn update_matrix(&mut self, uid: &String, matrix: Array3<Real>) -> bool {
         let real_idx = match self.matrices.iter().position(|c| c.uid == *uid) {
             Some(c) => c,
             None => {return false;}
@@ -196,11 +396,11 @@ impl Logger {
             None => {return false;}
         };
         self.push_correlation(eco, self.correlations.correlation[real_idx].nick.clone());
-        match self.tomls.iter().position(|t| t.uid == self.correlations.correlation[real_idx].toml) {
-            Some(t) => self.tomls.remove(t),
+        let kernels = match self.tomls.iter().position(|t| t.uid == self.correlations.correlation[real_idx].toml) {
+            Some(t) => LayerData::kernel_ids(&self.tomls.remove(t).layer),
             None => return false,
         };
-        self.correlations.correlation[real_idx].matrix.iter().for_each(|m|{
+        self.correlations.correlation[real_idx].matrix.iter().chain(&self.correlations.correlation[real_idx].mask).chain(&kernels).for_each(|m|{
             let i = self.matrices.iter().position(|mm| mm.uid == *m).unwrap();
             self.matrices.remove(i);
         });
@@ -210,6 +410,7 @@ impl Logger {
         }
         if option.1 {
             self.correlations.correlation[real_idx].matrix = self.correlations.correlation.last().unwrap().clone().matrix;
+            self.correlations.correlation[real_idx].mask = self.correlations.correlation.last().unwrap().clone().mask;
         }
         self.correlations.correlation.pop();
         true
@@ -236,9 +437,7 @@ impl Logger {
         };
         self.matrices.remove(real_idx);
         self.correlations.correlation.iter_mut().for_each(|c|{
-            c.matrix.iter_mut().for_each(|m|{
-                if *m == *uid {c.active = false;}
-            });
+            if c.matrix.iter().chain(&c.mask).any(|m| *m == *uid) {c.active = false;}
         });
         true
     }
@@ -247,11 +446,11 @@ impl Logger {
             Some(c) => c,
             None => {return false;}
         };
-        match self.tomls.iter().position(|t| t.uid == self.correlations.correlation[real_idx].toml) {
-            Some(t) => self.tomls.remove(t),
+        let kernels = match self.tomls.iter().position(|t| t.uid == self.correlations.correlation[real_idx].toml) {
+            Some(t) => LayerData::kernel_ids(&self.tomls.remove(t).layer),
             None => return false,
         };
-        self.correlations.correlation[real_idx].matrix.iter().for_each(|m|{
+        self.correlations.correlation[real_idx].matrix.iter().chain(&self.correlations.correlation[real_idx].mask).chain(&kernels).for_each(|m|{
             let i = self.matrices.iter().position(|mm| mm.uid == *m).unwrap();
             self.matrices.remove(i);
         });
@@ -280,58 +479,152 @@ impl Logger {
         let path = "data/matrix".to_owned();
         fs::create_dir_all(path.clone()).unwrap();
         self.matrices.clone().iter().for_each(|m|{
-            let rtx = self.image(&m.matrix.clone());
+            let rtx = self.image(m.matrix.index_axis(Axis(0), m.matrix.dim().0 / 2));  // middle slice as preview
             let tx = Texture2D::from_rgba8(rtx.0.0 as u16, rtx.0.1 as u16, rtx.1);
             let img = tx.get_texture_data();
             img.export_png(&(path.clone() + "/" + &m.uid.to_string() + ".png"));
             
-            let serialized: Vec<u8> = serialize(m).unwrap();
             let mut file = File::create(path.clone() + "/" + &m.uid.to_string() + ".bin").unwrap();
-            file.write_all(&serialized).unwrap();
+            file.write_all(&write_matrix(m)).unwrap();
         });
         (self.correlations.correlation.len(), self.tomls.len(), self.matrices.len())
     }
 
-    // returns amount of loaded instances
-    pub fn load_from_file(&mut self) -> (usize, usize, usize) {
-        self.tomls.clear();
+    // returns amount of loaded instances, on error nothing is replaced and message says which
+    // file (and layer and field of it) is wrong
+    pub fn load_from_file(&mut self) -> Result<(usize, usize, usize), String> {
+        let mut tomls = vec![];
+        let mut paths = vec![];
         let dir = match fs::read_dir("data/toml") {
             Ok(d) => d,
-            Err(_) => return (0,0,0),
+            Err(_) => return Ok((0,0,0)),
         };
         let mut entries = dir.map(|res| res.map(|e| e.path())).collect::<Result<Vec<_>, std::io::Error>>().unwrap();
         entries.sort_unstable();
         let entries = entries.iter().filter(|e| e.is_file() && e.file_name().unwrap().to_string_lossy().contains(".toml") ).collect::<Vec<_>>();
-        entries.iter().for_each(|e|{
+        for e in entries {
             let toml_raw = fs::read_to_string(e).unwrap();
-            let toml_data:TomlData = toml::from_str(&toml_raw).unwrap();
-            self.tomls.push(toml_data);
-        });
+            let toml_data: TomlData = toml::from_str(&toml_raw).map_err(|err| format!("{}: {}", e.display(), err))?;
+            if toml_data.tile == Some(0) { return Err(format!("{}: tile size has to be at least 1", e.display())); }
+            for (i, l) in toml_data.layer.iter().enumerate() {
+                l.kernel.validate().map_err(|err| format!("{}: layer {} kernel: {}", e.display(), i, err))?;
+                l.growth_map.validate().map_err(|err| format!("{}: layer {} growth_map: {}", e.display(), i, err))?;
+            }
+            tomls.push(toml_data);
+            paths.push(e.display().to_string());
+        }
 
-        self.matrices.clear();
-        let dir = match fs::read_dir("data/matrix") {
-            Ok(d) => d,
-            Err(_) => return (0,self.tomls.len(),0),
-        };
-        let mut entries = dir.map(|res| res.map(|e| e.path())).collect::<Result<Vec<_>, std::io::Error>>().unwrap();
-        entries.sort_unstable();
-        let entries = entries.iter().filter(|e| e.is_file() && e.file_name().unwrap().to_string_lossy().contains(".bin") ).collect::<Vec<_>>();
-        entries.iter().for_each(|e|{
-            let mut buffer = vec![];
-            let mut file = File::open(e).unwrap();
-            file.read_to_end(&mut buffer).unwrap();
-            let deserialized: MatrixData = deserialize(&buffer).unwrap();
-            self.matrices.push(deserialized);
-        });
+        let mut matrices = vec![];
+        if let Ok(dir) = fs::read_dir("data/matrix") {
+            let mut entries = dir.map(|res| res.map(|e| e.path())).collect::<Result<Vec<_>, std::io::Error>>().unwrap();
+            entries.sort_unstable();
+            let entries = entries.iter().filter(|e| e.is_file() && e.file_name().unwrap().to_string_lossy().contains(".bin") ).collect::<Vec<_>>();
+            for e in entries {
+                let mut buffer = vec![];
+                let mut file = File::open(e).unwrap();
+                file.read_to_end(&mut buffer).unwrap();
+                matrices.push(read_matrix(&buffer).map_err(|err| format!("{}: {}", e.display(), err))?);
+            }
+        }
 
-        self.correlations.correlation.clear();
-        let toml_raw = match fs::read_to_string("data/correlations.toml") {
-            Ok(d) => d,
-            Err(_) => return (0,self.tomls.len(),self.matrices.len()),
+        // imported kernels become matrices like any other, layer refers to them by uid from now on
+        for (t, toml_path) in tomls.iter_mut().zip(&paths) {
+            for (i, l) in t.layer.iter_mut().enumerate() {
+                if let Some(path) = l.kernel_image.take() {
+                    let matrix = read_kernel(&path).map_err(|err| format!("{}: layer {} kernel_image {}: {}", toml_path, i, path, err))?;
+                    let uid = self.gen.next_id();
+                    matrices.push(MatrixData { uid: uid.clone(), matrix });
+                    l.kernel_id = Some(uid);
+                }
+            }
+        }
+
+        // kernels are made once here, so one that can't be normalized is refused with its file
+        for (t, path) in tomls.iter().zip(&paths) {
+            for (i, l) in t.layer.iter().enumerate() {
+                let Some(input) = matrices.iter().find(|m| m.uid == l.matrix_id) else { continue; };
+                let Some(mut layer) = l.layer(0, 0, &matrices) else { continue; };  // correlation is unusable anyway
+                layer.generate_kernel_lookup(input.matrix.dim().0).map_err(|err| format!("{}: layer {} {}", path, i, err))?;
+            }
+        }
+
+        let correlations = match fs::read_to_string("data/correlations.toml") {
+            Ok(d) => toml::from_str(&d).map_err(|err| format!("data/correlations.toml: {}", err))?,
+            Err(_) => TomlCorrelations { correlation: vec![] },
         };
-        let correlation_data: TomlCorrelations = toml::from_str(&toml_raw).unwrap();
-        self.correlations = correlation_data;
-        (self.correlations.correlation.len(), self.tomls.len(), self.matrices.len())
+        self.tomls = tomls;
+        self.matrices = matrices;
+        self.correlations = correlations;
+        Ok((self.correlations.correlation.len(), self.tomls.len(), self.matrices.len()))
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lenia::{NoiseKind, Shape};
+
+    fn noisy_eco() -> Eco {
+        let layer = Layer::new(
+            Function::new(Shape::GaussianBump, false, vec![0.15, 0.5]).unwrap(),
+            Function::new(Shape::GaussianBump, true, vec![0.15, 0.3]).unwrap(),
+            0, 0, 1., 5,
+        );
+        let mut eco = Eco::new((32, 32), 0.1, 0, vec![Channel::new(Array3::zeros((1, 32, 32)))], vec![layer]);
+        eco.noise = Some(Noise::new(NoiseKind::Gaussian, 0.05, 1.));
+        eco.reseed(7);
+        eco.soup(0, 0..1, 8..24, 8..24);
+        eco.init().unwrap();
+        eco
+    }
+
+    fn saved(values: MatrixValues) -> Vec<u8> {
+        let mut buffer = MATRIX_MAGIC.to_vec();
+        buffer.extend(serialize(&SavedMatrixData { uid: "m".to_string(), values }).unwrap());
+        buffer
+    }
+
+    #[test]
+    fn matrix_roundtrip() {
+        let matrix = Array3::from_shape_fn((3, 4, 5), |(z, x, y)| (z * 20 + x * 5 + y) as Real / 64.);
+        let read = read_matrix(&write_matrix(&MatrixData { uid: "m".to_string(), matrix: matrix.clone() })).unwrap();
+        assert_eq!((read.uid.as_str(), read.matrix), ("m", matrix));
+    }
+
+    // files of other precision and older versions, values are exact in both builds
+    #[test]
+    fn reads_every_matrix_version() {
+        let f32s = Array3::from_shape_fn((2, 3, 4), |(z, x, y)| (z * 12 + x * 4 + y) as f32 / 32.);
+        let expected = f32s.mapv(|x| x as Real);
+        let f64s = f32s.mapv(|x| x as f64);
+        let lnm3 = [F32_MATRIX_MAGIC, &serialize(&F32MatrixData { uid: "m".to_string(), matrix: f32s.clone() }).unwrap()].concat();
+        let flat = f32s.index_axis(Axis(0), 0).to_owned();
+        let legacy = serialize(&LegacyMatrixData { uid: "m".to_string(), matrix: flat.clone() }).unwrap();
+
+        assert_eq!(read_matrix(&saved(MatrixValues::F32(f32s.clone()))).unwrap().matrix, expected);
+        assert_eq!(read_matrix(&saved(MatrixValues::F64(f64s))).unwrap().matrix, expected);
+        assert_eq!(read_matrix(&lnm3).unwrap().matrix, expected);
+        assert_eq!(read_matrix(&legacy).unwrap().matrix, flat.insert_axis(Axis(0)).mapv(|x| x as Real));
+        assert!(read_matrix(b"LNM4 not a matrix").is_err());
+    }
+
+    // run saved midway and reloaded through toml text goes on like the one that wasn't stopped
+    #[test]
+    fn reload_continues_rng() {
+        let mut eco = noisy_eco();
+        for _ in 0..3 { eco.evaluate(); }
+        let mut logger = Logger::new();
+        let uid = logger.push_correlation(&eco, "test".to_string()).clone();
+        let text = toml::to_string(&logger.tomls[0]).unwrap();
+        logger.tomls[0] = toml::from_str(&text).unwrap();
+
+        let mut loaded = logger.get_correlation(&uid).unwrap();
+        loaded.init().unwrap();
+        for _ in 0..3 {
+            eco.evaluate();
+            loaded.evaluate();
+        }
+        assert_eq!(eco.channels[0].matrix, loaded.channels[0].matrix);
+    }
+}
@@ -1,11 +1,14 @@
 //#![allow(clippy::ptr_arg)]
 use macroquad::prelude::*;
 use ndarray::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 mod utils;
+mod fft;
+mod expr;
 
 mod lenia;
-use lenia::{Channel, Eco, Function, Layer, Shape};
+use lenia::{Channel, Eco, Function, Layer, Real, Shape};
 
 mod logger;
 use logger::Logger;
@@ -15,22 +18,20 @@ use ui::UI;
 
 // TODO: UI creator with fitness graph, tuning AI
 
-fn _creator(size: (usize, usize)) -> Eco {
+// depth 1 makes flat world, the same seed makes the same world
+fn _creator(size: (usize, usize), depth: usize, seed: u64) -> Eco {
     let mut eco = Eco::new((size.0, size.1), 0.1, 0, vec![], vec![]);
-    let mut matrix = Array2::<f32>::zeros(size);
+    eco.reseed(seed);
+    eco.channels.push( Channel::new(Array3::<Real>::zeros((depth, size.0, size.1))) );
+    let z_range = if depth > 1 {(depth as f32 * 0.4)as usize..(depth as f32 * 0.6)as usize} else {0..1};
 
     // generate random starting point
-    for x in (size.0 as f32 * 0.1)as usize..(size.0 as f32 * 0.2)as usize{ 
-        for y in (size.1 as f32 * 0.1)as usize..(size.1 as f32 * 0.2)as usize{ 
-            matrix[[x as usize, y as usize]] = rand::gen_range(0.0,1.0);
-        }
-    } 
-    for x in (size.0 as f32 * 0.7)as usize..(size.0 as f32 * 0.8)as usize{ 
-        for y in (size.1 as f32 * 0.4)as usize..(size.1 as f32 * 0.55)as usize{ 
-            matrix[[x as usize, y as usize]] = rand::gen_range(0.0,1.0);
-        }
-    } 
-    eco.channels.push( Channel::new(matrix.clone()) );
+    eco.soup(0, z_range.clone(), 
+        (size.0 as f32 * 0.1)as usize..(size.0 as f32 * 0.2)as usize, 
+        (size.1 as f32 * 0.1)as usize..(size.1 as f32 * 0.2)as usize);
+    eco.soup(0, z_range, 
+        (size.0 as f32 * 0.7)as usize..(size.0 as f32 * 0.8)as usize, 
+        (size.1 as f32 * 0.4)as usize..(size.1 as f32 * 0.55)as usize);
 
     // width, offset
     //let mut f = vec![];
@@ -42,9 +43,9 @@ fn _creator(size: (usize, usize)) -> Eco {
 
     eco.layers.push(
         Layer::new(
-            Function::new(Shape::MexicanHat, false, vec![0.16, 0.5]), 
-            Function::new(Shape::GaussianBump, true, vec![0.12, 0.5]), 
-            0, 64
+            Function::new(Shape::MexicanHat, false, vec![0.16, 0.5]).unwrap(), 
+            Function::new(Shape::GaussianBump, true, vec![0.12, 0.5]).unwrap(), 
+            0, 0, 1., 64
         ) 
     );
     eco
@@ -58,10 +59,17 @@ async fn main() {
 
     // LOGGER
     let mut logger = Logger::new();
-    logger.load_from_file();
+    if let Err(e) = logger.load_from_file() { println!("{}", e); }
     let mut uid = "sr6X529DRyGIS1bqOSydCR".to_string();
-    let mut eco = _creator(window_size);//logger.get_correlation(&uid).unwrap();
-    eco.init();
+    // seed can be given as first argument, otherwise it's taken from clock
+    // toml integers are i64, so bigger seeds couldn't be saved
+    let seed = std::env::args().nth(1).and_then(|s| s.parse::<u64>().ok()).filter(|s| *s <= i64::MAX as u64)
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+    println!("seed: {}", seed);
+    // depth of world is second argument, 1 (flat world) when not given
+    let depth = std::env::args().nth(2).and_then(|s| s.parse().ok()).unwrap_or(1usize).max(1);
+    let mut eco = _creator(window_size, depth, seed);//logger.get_correlation(&uid).unwrap();
+    eco.init().unwrap();
     //logger.push_correlation(&eco, "MK".to_string());
     //logger.save_to_file();
     // LOGGER
@@ -76,9 +84,15 @@ async fn main() {
             eco.evaluate();
         }
 
-        let rtx = logger.image(&eco.channels[0].matrix);       
+        let z = ui.z.min(eco.depth() - 1);
+        let rtx = logger.image(eco.channels[0].matrix.index_axis(Axis(0), z));       
         let tx = Texture2D::from_rgba8(rtx.0.0 as u16, rtx.0.1 as u16, rtx.1);
         draw_texture(&tx, ui_offset, 0., WHITE);
+        if let Some(mask) = &eco.mask {
+            let rtx = logger.mask_image(mask.index_axis(Axis(0), z));
+            let tx = Texture2D::from_rgba8(rtx.0.0 as u16, rtx.0.1 as u16, rtx.1);
+            draw_texture(&tx, ui_offset, 0., WHITE);
+        }
         //draw_texture_ex(&tx, ui_offset, 0., WHITE, DrawTextureParams{
         //    dest_size: Some(Vec2::new(1080., 1080.)),
         //    ..Default::default()
@@ -1,7 +1,8 @@
 use std::{fs::File, io::prelude::*, time::{Duration, SystemTime}};
 
-use crate::{lenia::{Eco, Cycle}, logger::Logger, utils::FrameTimeAnalyzer};
+use crate::{lenia::{Boundary, Eco, Cycle, Function, Noise, NoiseKind, Real}, logger::Logger, utils::FrameTimeAnalyzer};
 use macroquad::prelude::*;
+use ndarray::prelude::*;
 
 
 pub struct Menu {
@@ -45,13 +46,13 @@ impl Menu {
             uid.clear();
             uid.push_str(&list[self.field as usize].0);
             *eco = logger.get_correlation(&list[self.field as usize].0).unwrap();
-            eco.init();
+            if let Err(e) = eco.init() { println!("{}", e); }
             self.active = false;
         }
         if is_key_pressed(KeyCode::Delete) {
             logger.pop_correlation(&list[self.field as usize].0);
             logger.save_to_file();
-            logger.load_from_file();
+            reload(logger);
         }
         if is_key_pressed(KeyCode::Insert) {
             *eco = logger.get_correlation(&list[self.field as usize].0).unwrap();
@@ -59,15 +60,24 @@ impl Menu {
             uid.push_str(logger.push_correlation(eco, "new".to_string()));
 
             logger.save_to_file();
-            logger.load_from_file();
+            reload(logger);
         }
 
     }
 }
 
+// load errors are printed to console, they are too long for popup
+fn reload(logger: &mut Logger) -> bool {
+    match logger.load_from_file() {
+        Ok(_) => true,
+        Err(e) => { println!("{}", e); false },
+    }
+}
+
 pub struct UI {
     fta: FrameTimeAnalyzer,
     pub pause: bool,
+    pub z: usize,  // shown slice of 3d world
     popup: Popup,
     font: Font,
     dd: DynamicDisplay,
@@ -81,6 +91,7 @@ impl UI {
         UI { 
             fta: FrameTimeAnalyzer::new(16), 
             pause: false, 
+            z: 0,
             popup: Popup::new(),
             font,
             dd: DynamicDisplay::new(),
@@ -101,27 +112,51 @@ impl UI {
         self.fta.add_frame_time(get_frame_time()*1000.);
         draw_text_ex(&(self.fta.smooth_frame_time().round().to_string() + " ms"), 450., 30., tp.clone());
 
-        if !self.menu.active {self.dd.update(uid, eco, tp.clone());}
+        if !self.menu.active {
+            self.dd.update(uid, eco, tp.clone());
+            if let Some(e) = self.dd.error.take() { self.popup.show(&e, None); }
+            self.paint(eco);
+        }
         else {self.menu.run(uid, eco, logger, tp.clone());}
 
+        if let Some(n) = eco.noise {
+            draw_text_ex(&format!("Noise: {} {} (length {})", n.kind, n.amplitude, n.correlation), 24., 880., tp.clone());
+        }
         self.popup.update(tp.clone());
         if self.menu.active {self.pause = true;}  // pause simulation when menu
         tp.font_size = 64;
         if self.pause {draw_text_ex("PAUSE", 24., 850., tp.clone());}
+        if eco.depth() > 1 {draw_text_ex(&("z: ".to_owned() + &self.z.to_string() + "/" + &(eco.depth() - 1).to_string()), 280., 850., tp.clone());}
 
         self.decorations();
         self.input_handler(uid, eco, logger)
     }
 
+    // obstacles are painted with mouse on shown slice, left button puts walls, right erases
+    fn paint(&self, eco: &mut Eco) {
+        let (x, y) = mouse_position();
+        let (x, y) = (x - 512., y);
+        if x < 0. || y < 0. || x >= eco.size.0 as f32 || y >= eco.size.1 as f32 {return;}
+        let position = [self.z, x as usize, y as usize];
+        if is_mouse_button_down(MouseButton::Left) {eco.paint_mask(position, 8, true);}
+        if is_mouse_button_down(MouseButton::Right) {eco.paint_mask(position, 8, false);}
+    }
+
     fn decorations(&mut self) {
         draw_line(512., 0., 512., 1024.-128., 4., BLACK);
     }
 
     fn input_handler(&mut self, uid: &mut String, eco: &mut Eco, logger: &mut Logger) -> bool {
         if is_key_pressed(KeyCode::P) {self.pause = !self.pause;}
+        if is_key_pressed(KeyCode::LeftBracket) {self.z = self.z.saturating_sub(1);}
+        if is_key_pressed(KeyCode::RightBracket) {self.z += 1;}
+        self.z = self.z.min(eco.depth() - 1);
         
         if is_key_pressed(KeyCode::S) { logger.save_to_file(); self.popup.show(&"SAVED".to_string(), None);}
-        if is_key_pressed(KeyCode::L) { logger.load_from_file(); self.popup.show(&"LOADED".to_string(), None);}
+        if is_key_pressed(KeyCode::L) {
+            let text = if reload(logger) {"LOADED"} else {"LOAD FAILED"};
+            self.popup.show(&text.to_string(), None);
+        }
         if is_key_pressed(KeyCode::U) { 
             logger.update_correlation(uid, &eco, (true, true)); 
             self.popup.show(&"UPDATED".to_string(), None);
@@ -133,7 +168,7 @@ impl UI {
         }
         if is_key_pressed(KeyCode::Escape) { 
             logger.save_to_file();
-            logger.load_from_file();
+            reload(logger);
             self.menu.active = true;
         }
         
@@ -180,6 +215,12 @@ impl Popup {
 }
 
 
+// function values on 0<>1 scaled to pixels, cast is needed only in f64 build
+#[allow(clippy::unnecessary_cast)]
+fn plot(function: &Function, shape: &mut [f32; 100]) {
+    shape.iter_mut().enumerate().for_each(|(i, x)| *x = 100. * function.calc(i as Real/100.) as f32 );
+}
+
 pub struct DynamicDisplay {
     field: isize,  // field number
     field_old: isize,
@@ -192,6 +233,8 @@ pub struct DynamicDisplay {
     parameters_lengths: Vec<usize>,
     kernel_shape: [f32; 100],
     growth_map_shape: [f32; 100],
+    kernel_image: Option<(Array2<Real>, Texture2D)>,  // middle slice of kernel lookup, remade when it changes
+    pub error: Option<String>,  // last kernel that couldn't be made, shown in popup
 }
 
 // horrible code, idk how it works
@@ -199,9 +242,14 @@ impl DynamicDisplay {
     pub fn new() -> Self {
         Self { field: 0, field_old: -1, layer_num: 0, is_kernel: false, idx: 0, uid_old: String::new(), field_max: 0, pos_y: 0., 
             parameters_lengths: vec![], 
-            kernel_shape: [0.;100], growth_map_shape: [0.;100] 
+            kernel_shape: [0.;100], growth_map_shape: [0.;100], kernel_image: None, error: None,
         }
     }
+    // layer whose kernel can't be made keeps its old one
+    fn init(&mut self, eco: &mut Eco) {
+        if let Err(e) = eco.init() { self.error = Some(e); }
+    }
+
     pub fn update(&mut self, uid: &String, eco: &mut Eco, tp: TextParams){
         let mut tp = tp;
 
@@ -235,8 +283,8 @@ impl DynamicDisplay {
             }
             
             // after possible layer change and after parameter change (below)
-            self.kernel_shape.iter_mut().enumerate().for_each(|(i, x)| *x = 100. * eco.layers[self.layer_num].kernel.calc(i as f32/100.) );
-            self.growth_map_shape.iter_mut().enumerate().for_each(|(i, x)| *x = 100. * eco.layers[self.layer_num].growth_map.calc(i as f32/100.) );
+            plot(&eco.layers[self.layer_num].kernel, &mut self.kernel_shape);
+            plot(&eco.layers[self.layer_num].growth_map, &mut self.growth_map_shape);
         }
         self.field_old = self.field;
         self.uid_old = uid.to_string();
@@ -260,33 +308,83 @@ impl DynamicDisplay {
         if is_key_pressed(KeyCode::PageUp) {
             self.field_old = -10;   // force refresh
             if self.is_kernel {
-                eco.layers[self.layer_num].kernel.shape = eco.layers[self.layer_num].kernel.shape.next();
+                let shape = eco.layers[self.layer_num].kernel.shape.next();
+                eco.layers[self.layer_num].kernel.set_shape(shape);
             } else {
-                eco.layers[self.layer_num].growth_map.shape = eco.layers[self.layer_num].growth_map.shape.next();
+                let shape = eco.layers[self.layer_num].growth_map.shape.next();
+                eco.layers[self.layer_num].growth_map.set_shape(shape);
             }
-            eco.init();  // need to regenerate kernel lookup
+            self.init(eco);  // need to regenerate kernel lookup
         }
         if is_key_pressed(KeyCode::PageDown) {
             self.field_old = -10;   // force refresh
             if self.is_kernel {
-                eco.layers[self.layer_num].kernel.shape = eco.layers[self.layer_num].kernel.shape.previous();
+                let shape = eco.layers[self.layer_num].kernel.shape.previous();
+                eco.layers[self.layer_num].kernel.set_shape(shape);
             } else {
-                eco.layers[self.layer_num].growth_map.shape = eco.layers[self.layer_num].growth_map.shape.previous();
+                let shape = eco.layers[self.layer_num].growth_map.shape.previous();
+                eco.layers[self.layer_num].growth_map.set_shape(shape);
             }
-            eco.init();  // need to regenerate kernel lookup
+            self.init(eco);  // need to regenerate kernel lookup
         }
 
+        // channel coupling, input and target channel are cycled, weight is changed in steps
+        if is_key_pressed(KeyCode::C) {
+            let l = &mut eco.layers[self.layer_num];
+            l.channel_id = (l.channel_id + 1) % eco.channels.len();
+        }
+        if is_key_pressed(KeyCode::T) {
+            let l = &mut eco.layers[self.layer_num];
+            l.target_id = (l.target_id + 1) % eco.channels.len();
+        }
+        if is_key_pressed(KeyCode::M) { eco.mode = eco.mode.next(); }
+        if is_key_pressed(KeyCode::I) { eco.integrator = eco.integrator.next(); }
+        if is_key_pressed(KeyCode::B) { eco.boundary = eco.boundary.next(); }
+        if is_key_pressed(KeyCode::E) { eco.mask_exclude = !eco.mask_exclude; }
+        if is_key_pressed(KeyCode::O) { eco.mask = None; }
+        if is_key_pressed(KeyCode::R) {
+            eco.noise = match eco.noise {
+                None => Some(Noise::new(NoiseKind::Gaussian, 0.01, 0.)),
+                Some(n) if n.kind == NoiseKind::Gaussian => Some(Noise { kind: NoiseKind::Uniform, ..n }),
+                Some(_) => None,
+            };
+        }
+        // angular kernel modulation, harmonics cycle 0 (off) to 6, angle turns by 15 degrees
+        if is_key_pressed(KeyCode::K) {
+            let a = &mut eco.layers[self.layer_num].anisotropy;
+            a.harmonics = (a.harmonics + 1) % 7;
+            if a.strength == 0. { a.strength = 0.5; }
+            self.init(eco);  // need to regenerate kernel lookup
+        }
+        if is_key_pressed(KeyCode::J) {
+            eco.layers[self.layer_num].anisotropy.angle += std::f64::consts::PI as Real / 12.;
+            self.init(eco);  // need to regenerate kernel lookup
+        }
+        // kernel supersampling, 1 (cell centers) to 8x8 points per cell
+        if is_key_pressed(KeyCode::H) {
+            let l = &mut eco.layers[self.layer_num];
+            l.samples = if l.samples >= 8 {1} else {l.samples * 2};
+            self.init(eco);  // need to regenerate kernel lookup
+        }
+        if is_key_pressed(KeyCode::G) { eco.tile = match eco.tile { Some(_) => None, None => Some(64) }; }
+        if is_key_pressed(KeyCode::A) { 
+            eco.tolerance = match eco.tolerance { Some(_) => None, None => Some(0.01) };
+            eco.effective_delta = eco.delta;
+        }
+        if is_key_pressed(KeyCode::Minus) { eco.layers[self.layer_num].weight -= 0.05; }
+        if is_key_pressed(KeyCode::Equal) { eco.layers[self.layer_num].weight += 0.05; }
+
         if is_key_pressed(KeyCode::Left) || is_key_pressed(KeyCode::Right){
             let mut value = 0.02;
             if self.idx == 0 {value = 0.002;}
             if is_key_pressed(KeyCode::Left) {value = -value;}
             if self.is_kernel {
-                eco.layers[self.layer_num].kernel.parameters[self.idx] += value;
+                eco.layers[self.layer_num].kernel.adjust(self.idx, value);
             }
-            else {eco.layers[self.layer_num].growth_map.parameters[self.idx] += value;}
-            self.kernel_shape.iter_mut().enumerate().for_each(|(i, x)| *x = 100. * eco.layers[self.layer_num].kernel.calc(i as f32/100.) );
-            self.growth_map_shape.iter_mut().enumerate().for_each(|(i, x)| *x = 100. * eco.layers[self.layer_num].growth_map.calc(i as f32/100.) );
-            eco.init();  // need to regenerate kernel lookup
+            else {eco.layers[self.layer_num].growth_map.adjust(self.idx, value);}
+            plot(&eco.layers[self.layer_num].kernel, &mut self.kernel_shape);
+            plot(&eco.layers[self.layer_num].growth_map, &mut self.growth_map_shape);
+            self.init(eco);  // need to regenerate kernel lookup
         } 
 
 
@@ -294,14 +392,26 @@ impl DynamicDisplay {
         tp.font_size = 20;
         let mut pos_y = 1.;
         draw_text_ex(&("Correlation: ".to_owned() + uid) , 8., pos_y * tp.font_size as f32, tp.clone()); pos_y+=1.;
-        draw_text_ex(&("Delta: ".to_owned() + &eco.delta.to_string()) , 8., pos_y * tp.font_size as f32, tp.clone()); pos_y+=1.;
+        draw_text_ex(&("Mode: ".to_owned() + &eco.mode.to_string() + " " + &eco.integrator.to_string()) , 260., pos_y * tp.font_size as f32, tp.clone());
+        let delta = match eco.tolerance {
+            Some(_) => eco.delta.to_string() + " (" + &format!("{:.4}", eco.effective_delta) + ")",
+            None => eco.delta.to_string(),
+        };
+        draw_text_ex(&("Delta: ".to_owned() + &delta) , 8., pos_y * tp.font_size as f32, tp.clone()); pos_y+=1.;
+        draw_text_ex(&("Mass error: ".to_owned() + &format!("{:.1e}", eco.mass_error)) , 260., pos_y * tp.font_size as f32, tp.clone());
         draw_text_ex(&("Fitness: ".to_owned() + &eco.fitness.to_string()) , 8., pos_y * tp.font_size as f32, tp.clone()); pos_y+=1.;
 
         let layer_num = self.layer_num;
         draw_circle(64., self.pos_y as f32 * tp.font_size as f32, 4., GREEN);
 
+        draw_text_ex(&("Time: ".to_owned() + &format!("{:.2}", eco.time)) , 260., pos_y * tp.font_size as f32, tp.clone()); 
         draw_text_ex(&("Layers amount: ".to_owned() + &eco.layers.len().to_string()) , 8., pos_y * tp.font_size as f32, tp.clone()); 
         pos_y+=1.;
+        let boundary = match eco.boundary {
+            Boundary::Constant(c) => eco.boundary.to_string() + " " + &c.to_string(),
+            _ => eco.boundary.to_string(),
+        };
+        draw_text_ex(&("Boundary: ".to_owned() + &boundary) , 260., pos_y * tp.font_size as f32, tp.clone()); 
         draw_text_ex(&("Layer: ".to_owned() + &layer_num.to_string()) , 8., pos_y * tp.font_size as f32, tp.clone()); 
         pos_y+=1.;
         draw_text_ex(&("Growth Map: : ") , 24., pos_y * tp.font_size as f32, tp.clone()); 
@@ -309,7 +419,7 @@ impl DynamicDisplay {
         draw_text_ex(&("- shape - ".to_owned() + &eco.layers[layer_num].growth_map.shape.to_string()) , 
             48., pos_y * tp.font_size as f32, tp.clone()); 
         pos_y+=1.;
-        draw_text_ex(&("- params (width, offset) - ".to_owned()) , 48., pos_y * tp.font_size as f32, tp.clone()); 
+        draw_text_ex(&("- params - ".to_owned()) , 48., pos_y * tp.font_size as f32, tp.clone()); 
 
         self.growth_map_shape.iter().enumerate().for_each(|(x,y)|{
             draw_rectangle(300.+x as f32 * 2., pos_y * tp.font_size as f32, 2., -*y, BLACK);
@@ -318,24 +428,52 @@ impl DynamicDisplay {
         draw_rectangle(300. + 100. - 0.5, pos_y * tp.font_size as f32 - 100., 1., 200., Color::from_rgba(255, 255, 255, 48));
 
         pos_y+=1.;
-        eco.layers[layer_num].growth_map.parameters.iter().for_each(|p|{
-            draw_text_ex(&( ((p * 1000.).round() / 1000.).to_string() ), 72., pos_y * tp.font_size as f32, tp.clone()); 
+        let function = &eco.layers[layer_num].growth_map;
+        function.shape.parameters(function.parameters.len()).iter().zip(&function.parameters).for_each(|(p, v)|{
+            draw_text_ex(&( p.name.clone() + ": " + &((v * 1000.).round() / 1000.).to_string() ), 72., pos_y * tp.font_size as f32, tp.clone()); 
             pos_y+=1.;
         });
         draw_text_ex(&("Kernel: ") , 24., pos_y * tp.font_size as f32, tp.clone()); 
         pos_y+=1.;
         draw_text_ex(&("- shape - ".to_owned() + &eco.layers[layer_num].kernel.shape.to_string()) , 48., pos_y * tp.font_size as f32, tp.clone()); 
         pos_y+=1.;
-        draw_text_ex(&("- params (width, offset) - ".to_owned()) , 48., pos_y * tp.font_size as f32, tp.clone()); 
+        draw_text_ex(&("- params - ".to_owned()) , 48., pos_y * tp.font_size as f32, tp.clone()); 
         pos_y+=1.;
-        eco.layers[layer_num].kernel.parameters.iter().for_each(|p|{
-            draw_text_ex(&( ((p * 1000.).round() / 1000.).to_string() ), 72., pos_y * tp.font_size as f32, tp.clone()); 
+        let function = &eco.layers[layer_num].kernel;
+        function.shape.parameters(function.parameters.len()).iter().zip(&function.parameters).for_each(|(p, v)|{
+            draw_text_ex(&( p.name.clone() + ": " + &((v * 1000.).round() / 1000.).to_string() ), 72., pos_y * tp.font_size as f32, tp.clone()); 
             pos_y+=1.;
         });
-        draw_text_ex(&("Channel number: ".to_owned() + &eco.layers[layer_num].channel_id.to_string()) , 
+        if !eco.layers[layer_num].kernel.rings.is_empty() {
+            draw_text_ex(&("- rings - ".to_owned() + &format!("{:?}", eco.layers[layer_num].kernel.rings)) , 
+                48., pos_y * tp.font_size as f32, tp.clone()); 
+            pos_y+=1.;
+        }
+        draw_text_ex(&("Channel number: ".to_owned() + &eco.layers[layer_num].channel_id.to_string() + 
+            " -> " + &eco.layers[layer_num].target_id.to_string()) , 
             24., pos_y * tp.font_size as f32, tp.clone());
+        let tiles = match (eco.tile, eco.tiles) {
+            (None, _) => "off".to_owned(),
+            (Some(_), None) => "dense".to_owned(),
+            (Some(_), Some((active, all))) => active.to_string() + "/" + &all.to_string(),
+        };
+        draw_text_ex(&("Tiles: ".to_owned() + &tiles) , 260., pos_y * tp.font_size as f32, tp.clone());
+        pos_y+=1.;
+        draw_text_ex(&("Weight: ".to_owned() + &(((eco.layers[layer_num].weight * 1000.).round() / 1000.).to_string())) , 
+            24., pos_y * tp.font_size as f32, tp.clone());
+        draw_text_ex(&("Convolution: ".to_owned() + &eco.layers[layer_num].convolution.to_string()) , 
+            260., pos_y * tp.font_size as f32, tp.clone());
         
-        pos_y += 3.;
+        pos_y+=1.;
+        let a = eco.layers[layer_num].anisotropy;
+        let anisotropy = if a.harmonics == 0 && a.aspect == 1. {"off".to_owned()} else {
+            format!("{}-fold {} at {:.0} deg", a.harmonics, a.strength, a.angle.to_degrees())
+        };
+        draw_text_ex(&("Anisotropy: ".to_owned() + &anisotropy + ", samples: " + &eco.layers[layer_num].samples.to_string()) , 
+            24., pos_y * tp.font_size as f32, tp.clone());
+        self.kernel_image(eco, pos_y * tp.font_size as f32 + 8.);
+
+        pos_y += 1.;
         self.kernel_shape.iter().enumerate().for_each(|(x,y)|{
             draw_rectangle(300.+x as f32 * 2., pos_y * tp.font_size as f32, 2., -*y, BLACK);
         });
@@ -343,4 +481,27 @@ impl DynamicDisplay {
         draw_rectangle(300. + 100. - 0.5, pos_y * tp.font_size as f32 - 100., 1., 200., Color::from_rgba(255, 255, 255, 48));
 
     }
+
+    // kernel as seen by layer (with anisotropy), white is positive, red negative, both scaled
+    // to strongest cell
+    fn kernel_image(&mut self, eco: &Eco, y: f32) {
+        let lookup = eco.layers[self.layer_num].kernel_lookup();
+        let slice = lookup.index_axis(Axis(0), lookup.dim().0 / 2);
+        if self.kernel_image.as_ref().is_none_or(|(k, _)| *k != slice) {
+            let max = slice.iter().fold(0., |a: Real, k| a.max(k.abs()));
+            let (w, h) = slice.dim();
+            let mut bytes = vec![0; w * h * 4];
+            bytes.chunks_mut(4).enumerate().for_each(|(i, px)|{
+                let k = slice[[i % w, i / w]] / max;
+                let v = (k.abs() * 255.) as u8;
+                px.copy_from_slice(&if k < 0. {[v, 0, 0, 255]} else {[v, v, v, 255]});
+            });
+            let tx = Texture2D::from_rgba8(w as u16, h as u16, &bytes);
+            tx.set_filter(FilterMode::Nearest);
+            self.kernel_image = Some((slice.to_owned(), tx));
+        }
+        if let Some((_, tx)) = &self.kernel_image {
+            draw_texture_ex(tx, 24., y, WHITE, DrawTextureParams { dest_size: Some(vec2(128., 128.)), ..Default::default() });
+        }
+    }
 }